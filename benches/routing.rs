@@ -0,0 +1,51 @@
+//! Cost of routing a filesystem event to replicas, along with ticks, by number of replicas.
+//!
+//! Run with `cargo bench --bench routing`.
+
+use std::io::sink;
+use std::path::PathBuf;
+use std::time::Instant;
+use unison_fsmonitor::{Event, EventKind, FsEvent, Monitor, Request, Watch};
+
+struct Watcher {}
+
+impl Watch for Watcher {}
+
+const EVENTS: u32 = 100_000;
+
+fn main() {
+    for replicas in [1, 10, 100, 1000, 10000] {
+        let mut monitor = Monitor::new(Watcher {}, sink());
+        for idx in 0..replicas {
+            let request = Request::Start {
+                replica: idx.to_string(),
+                root: PathBuf::from(format!("/bench/replica-{}", idx)),
+                subdir: None,
+            };
+            monitor
+                .handle_event(Event::Input(request.to_string()))
+                .unwrap();
+        }
+
+        let path = PathBuf::from(format!("/bench/replica-{}/subdir/filename", replicas / 2));
+        let start = Instant::now();
+        for _ in 0..EVENTS {
+            monitor
+                .handle_event(Event::FSEvent(FsEvent::new(
+                    EventKind::ModifyData,
+                    &path.clone(),
+                )))
+                .unwrap();
+            // As Monitor::run does.
+            let now = Instant::now();
+            if monitor.next_tick(now).is_zero() {
+                monitor.handle_event(Event::Tick(now)).unwrap();
+            }
+        }
+        println!(
+            "{:>6} replicas: {:>8.0?} per event",
+            replicas,
+            start.elapsed() / EVENTS
+        );
+    }
+}
@@ -0,0 +1,437 @@
+//! Diagnostics of the environment of a root, for the `check` command: watch limits, permissions,
+//! filesystem and delivery of events by the watching backend.
+
+use crate::event::FsEvent;
+use crate::limits::{
+    is_watch_limit, max_user_watches, user_watches_in_use, watch_limit_diagnostic,
+};
+use crate::mounts::{Mount, mount_of, read_mounts};
+use crate::watch::{Backend, BackendOptions, BackendWatcher, Watch};
+use failure::Fallible;
+use notify::RecursiveMode;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, channel};
+use std::time::{Duration, Instant};
+
+/// How long native events may take to arrive.
+const EVENT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Outcome of a check, ordered by severity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Pass,
+    /// Works, possibly not as expected.
+    Warn,
+    /// Changes won't be reported.
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub status: Status,
+    /// What was checked.
+    pub subject: &'static str,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(status: Status, subject: &'static str, message: String) -> Self {
+        Self {
+            status,
+            subject,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}  {}: {}", self.status, self.subject, self.message)
+    }
+}
+
+/// Directories under a root, as many as native watches needed to watch it.
+#[derive(Debug, Default, PartialEq)]
+pub struct DirCount {
+    /// Directories including the root.
+    pub dirs: usize,
+    /// Directories which can't be listed.
+    pub unreadable: Vec<PathBuf>,
+}
+
+/// Counts the directories under root, without following symbolic links.
+pub fn count_dirs(root: &Path) -> DirCount {
+    let mut count = DirCount::default();
+    let mut stack = vec![root.to_owned()];
+    while let Some(dir) = stack.pop() {
+        count.dirs += 1;
+        let Ok(entries) = fs::read_dir(&dir) else {
+            count.unreadable.push(dir);
+            continue;
+        };
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                stack.push(entry.path());
+            }
+        }
+    }
+    count.unreadable.sort();
+    count
+}
+
+pub fn check_permissions(root: &Path, count: &DirCount) -> Finding {
+    match count.unreadable.first() {
+        None => Finding::new(
+            Status::Pass,
+            "permissions",
+            format!("all {} directories are readable", count.dirs),
+        ),
+        Some(dir) if dir == root => Finding::new(
+            Status::Fail,
+            "permissions",
+            format!("unable to list root {:?}", root),
+        ),
+        Some(dir) => Finding::new(
+            Status::Warn,
+            "permissions",
+            format!(
+                "{} directories can't be listed, e.g., {:?}, changes in them aren't reported",
+                count.unreadable.len(),
+                dir
+            ),
+        ),
+    }
+}
+
+/// Compares the directories to watch with the inotify watches left, out of max per user.
+pub fn check_watch_limit(dirs: usize, max: Option<usize>, in_use: usize) -> Finding {
+    let Some(max) = max else {
+        return Finding::new(
+            Status::Pass,
+            "watch limit",
+            format!("{} directories, no inotify limit on this system", dirs),
+        );
+    };
+    let available = max.saturating_sub(in_use);
+    let usage = format!(
+        "{} directories to watch, {} of fs.inotify.max_user_watches={} available ({} in use)",
+        dirs, available, max, in_use
+    );
+    if dirs > available {
+        Finding::new(
+            Status::Fail,
+            "watch limit",
+            format!(
+                "{}, raise the limit, e.g., `sudo sysctl fs.inotify.max_user_watches=524288`",
+                usage
+            ),
+        )
+    } else if dirs * 5 > available * 4 {
+        Finding::new(
+            Status::Warn,
+            "watch limit",
+            format!("{}, leaving little room for other replicas", usage),
+        )
+    } else {
+        Finding::new(Status::Pass, "watch limit", usage)
+    }
+}
+
+/// Check if the filesystem of root, mounted on mount, produces events with backend.
+pub fn check_filesystem(mount: Option<&Mount>, options: &BackendOptions) -> Finding {
+    let Some(mount) = mount else {
+        return Finding::new(
+            Status::Warn,
+            "filesystem",
+            "unable to find the mount of root".into(),
+        );
+    };
+    let filesystem = format!("{} filesystem {}", mount.fs_type, mount.source);
+    if !mount.is_remote() {
+        return Finding::new(Status::Pass, "filesystem", filesystem);
+    }
+    match options.backend {
+        Backend::Native | Backend::Fanotify => Finding::new(
+            Status::Fail,
+            "filesystem",
+            format!(
+                "{}, changes made on other hosts produce no events, use --backend auto or poll",
+                filesystem
+            ),
+        ),
+        Backend::Auto | Backend::Poll => Finding::new(
+            Status::Warn,
+            "filesystem",
+            format!(
+                "{}, polled every {}s, so changes are reported late",
+                filesystem,
+                options.poll_interval.as_secs_f64()
+            ),
+        ),
+    }
+}
+
+/// Watches root with watcher and creates a file in it, expecting an event for it from events
+/// within timeout.
+pub fn check_events<WATCH: Watch>(
+    watcher: &mut WATCH,
+    events: &Receiver<FsEvent>,
+    root: &Path,
+    timeout: Duration,
+) -> Finding {
+    if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+        let message = if is_watch_limit(&e) {
+            watch_limit_diagnostic(root)
+        } else {
+            format!("unable to watch root: {}", error_message(&e))
+        };
+        return Finding::new(Status::Fail, "events", message);
+    }
+
+    let path = root.join(format!(".unison-fsmonitor-check-{}", std::process::id()));
+    let start = Instant::now();
+    let finding = match fs::write(&path, "") {
+        Ok(()) => {
+            let received = wait_for(events, &path, start + timeout);
+            let _ = fs::remove_file(&path);
+            match received {
+                Some(elapsed) => Finding::new(
+                    Status::Pass,
+                    "events",
+                    format!("test event received after {}ms", elapsed.as_millis()),
+                ),
+                None => Finding::new(
+                    Status::Fail,
+                    "events",
+                    format!(
+                        "no event for test file {:?} within {}s",
+                        path,
+                        timeout.as_secs_f64()
+                    ),
+                ),
+            }
+        }
+        Err(e) => Finding::new(
+            Status::Warn,
+            "events",
+            format!("unable to create test file {:?}, not verified: {}", path, e),
+        ),
+    };
+    let _ = watcher.unwatch(root);
+    finding
+}
+
+/// Message of error, spelled out for errors of notify 4 which only display as deprecated.
+fn error_message(error: &failure::Error) -> String {
+    match error.downcast_ref::<notify::Error>() {
+        Some(notify::Error::Io(e)) => e.to_string(),
+        Some(notify::Error::Generic(message)) => message.clone(),
+        Some(notify::Error::PathNotFound) => "path not found".into(),
+        Some(notify::Error::WatchNotFound) => "watch not found".into(),
+        None => error.to_string(),
+    }
+}
+
+/// Time until an event for path arrives, if before deadline.
+fn wait_for(events: &Receiver<FsEvent>, path: &Path, deadline: Instant) -> Option<Duration> {
+    let start = Instant::now();
+    loop {
+        let event = events.recv_timeout(deadline.checked_duration_since(Instant::now())?);
+        if event.ok()?.paths.iter().any(|p| p == path) {
+            return Some(start.elapsed());
+        }
+    }
+}
+
+/// Runs all checks of root, which has to be canonical, with the backend of options.
+pub fn check(root: &Path, options: &BackendOptions) -> Fallible<Vec<Finding>> {
+    let mounts = read_mounts();
+    let mount = mount_of(&mounts, root);
+    let polled = match options.backend {
+        Backend::Poll => true,
+        Backend::Auto => mount.is_some_and(Mount::is_remote),
+        Backend::Native | Backend::Fanotify => false,
+    };
+
+    let (tx, rx) = channel();
+    let mut watcher = BackendWatcher::new(options, tx)?;
+
+    let count = count_dirs(root);
+    let mut findings = vec![check_permissions(root, &count)];
+    if polled {
+        findings.push(Finding::new(
+            Status::Pass,
+            "watch limit",
+            format!("{} directories, polled without watches", count.dirs),
+        ));
+    } else if watcher.uses_fanotify(root) {
+        findings.push(Finding::new(
+            Status::Pass,
+            "watch limit",
+            format!(
+                "{} directories, watched with fanotify without inotify watches",
+                count.dirs
+            ),
+        ));
+    } else {
+        findings.push(check_watch_limit(
+            count.dirs,
+            max_user_watches(),
+            user_watches_in_use(),
+        ));
+    }
+    if !mounts.is_empty() {
+        findings.push(check_filesystem(mount, options));
+    }
+
+    let timeout = if polled {
+        EVENT_TIMEOUT + options.poll_interval * 2
+    } else {
+        EVENT_TIMEOUT
+    };
+    findings.push(check_events(&mut watcher, &rx, root, timeout));
+    Ok(findings)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::poll::PollWatcher;
+
+    #[test]
+    fn test_count_dirs() {
+        let base =
+            std::env::temp_dir().join(format!("unison-fsmonitor-count-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("a/b")).unwrap();
+        fs::create_dir_all(base.join("c")).unwrap();
+        fs::write(base.join("a/file"), "").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("a", base.join("link")).unwrap();
+
+        let count = count_dirs(&base);
+        assert_eq!(
+            count,
+            DirCount {
+                dirs: 4,
+                unreadable: vec![]
+            }
+        );
+        assert_eq!(check_permissions(&base, &count).status, Status::Pass);
+
+        let missing = base.join("missing");
+        let count = count_dirs(&missing);
+        assert_eq!(check_permissions(&missing, &count).status, Status::Fail);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_check_watch_limit() {
+        assert_eq!(check_watch_limit(10, Some(100), 20).status, Status::Pass);
+        assert_eq!(check_watch_limit(70, Some(100), 20).status, Status::Warn);
+        assert_eq!(check_watch_limit(90, Some(100), 20).status, Status::Fail);
+        assert_eq!(check_watch_limit(10, Some(100), 200).status, Status::Fail);
+        assert_eq!(check_watch_limit(1000, None, 0).status, Status::Pass);
+        assert_eq!(
+            check_watch_limit(10, Some(100), 20).to_string(),
+            "PASS  watch limit: 10 directories to watch, 80 of fs.inotify.max_user_watches=100 \
+             available (20 in use)"
+        );
+    }
+
+    #[test]
+    fn test_check_filesystem() {
+        let mount = |fs_type: &str| Mount {
+            id: 1,
+            mount_point: "/mnt".into(),
+            fs_type: fs_type.into(),
+            source: "server:/export".into(),
+        };
+        let options = |backend| BackendOptions {
+            backend,
+            ..BackendOptions::default()
+        };
+
+        let nfs = mount("nfs4");
+        assert_eq!(
+            check_filesystem(Some(&nfs), &options(Backend::Native)).status,
+            Status::Fail
+        );
+        assert_eq!(
+            check_filesystem(Some(&nfs), &options(Backend::Auto)).status,
+            Status::Warn
+        );
+        assert_eq!(
+            check_filesystem(Some(&mount("ext4")), &options(Backend::Native)).status,
+            Status::Pass
+        );
+        assert_eq!(
+            check_filesystem(None, &options(Backend::Auto)).status,
+            Status::Warn
+        );
+    }
+
+    #[test]
+    fn test_check_events() {
+        let root =
+            std::env::temp_dir().join(format!("unison-fsmonitor-events-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        let (tx, rx) = channel();
+        let mut watcher = PollWatcher::new(tx, Duration::from_millis(10), 100);
+        let finding = check_events(&mut watcher, &rx, &root, Duration::from_secs(5));
+        assert_eq!(finding.status, Status::Pass, "{}", finding);
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+
+        // Nothing is watched.
+        struct Watcher {}
+        impl Watch for Watcher {}
+        let (_tx, rx) = channel();
+        let finding = check_events(&mut Watcher {}, &rx, &root, Duration::from_millis(50));
+        assert_eq!(finding.status, Status::Fail);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_check_fanotify() {
+        let root =
+            std::env::temp_dir().join(format!("unison-fsmonitor-fanotify-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        for backend in [Backend::Native, Backend::Fanotify] {
+            let options = BackendOptions {
+                backend,
+                ..BackendOptions::default()
+            };
+            let (tx, _rx) = channel();
+            let fanotify = BackendWatcher::new(&options, tx)
+                .unwrap()
+                .uses_fanotify(&root);
+            assert!(!fanotify || backend == Backend::Fanotify);
+
+            // The inotify limit doesn't matter to fanotify.
+            let findings = check(&root, &options).unwrap();
+            let limit = findings
+                .iter()
+                .find(|finding| finding.subject == "watch limit")
+                .unwrap();
+            assert_eq!(limit.message.contains("fanotify"), fanotify, "{}", limit);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,430 @@
+//! Configuration file, for settings unison can't pass as it launches the monitor without
+//! arguments.
+//!
+//! The file is read from `$XDG_CONFIG_HOME/unison-fsmonitor/config.toml`, or from the path in
+//! [`CONFIG_ENV`]. Every setting is optional,
+//!
+//! ```toml
+//! backend = "auto"
+//! normalization = "nfc"
+//! ignore_case = false
+//! log_file = "/tmp/unison-fsmonitor.log"
+//! # Seconds without events before unison is notified.
+//! debounce = 0.5
+//! # Changes of paths, relative to roots, matching these aren't reported.
+//! ignore = ["*.swp", ".git"]
+//!
+//! [limits]
+//! poll_interval = 2
+//! poll_batch = 1000
+//! keepalive_interval = 30
+//! idle_timeout = 90
+//! # Hooks running at the same time.
+//! hook_concurrency = 2
+//!
+//! # Overrides for replicas with a root matching the glob.
+//! [roots."/home/*/build/**"]
+//! debounce = 5
+//! ignore = ["target"]
+//!
+//! # Commands run with changed paths on stdin, see [`Hook`].
+//! [[roots."/home/*/site".hooks]]
+//! command = "curl -s -X POST localhost:8080/reload"
+//! # When unison fetches changes, or "settled" when events settled.
+//! on = "changes"
+//! timeout = 60
+//! ```
+
+use crate::hooks::{DEFAULT_TIMEOUT, Hook};
+use crate::normalize::Normalization;
+use crate::watch::BackendOptions;
+use failure::{Fallible, ResultExt, bail};
+use glob::{MatchOptions, Pattern};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Environment variable of the config file path, overriding the default one.
+pub const CONFIG_ENV: &str = "UNISON_FSMONITOR_CONFIG";
+
+/// `*` matches a single name, `**` any number of them.
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Default path of the config file, if any.
+pub fn config_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("unison-fsmonitor").join("config.toml"))
+}
+
+/// Settings of a replica, see [`ReplicaConfig::settings`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReplicaSettings {
+    /// How long events have to settle before unison is notified of changes.
+    pub debounce: Duration,
+    /// Patterns of paths relative to the root whose changes aren't reported. Patterns without a
+    /// `/` match names at any depth. Everything under a matching path is ignored as well.
+    pub ignore: Vec<Pattern>,
+    /// Commands run on batches of changes.
+    pub hooks: Vec<Hook>,
+}
+
+impl ReplicaSettings {
+    /// Check if changes of path, relative to the root, are ignored.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        path.ancestors()
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .any(|ancestor| {
+                self.ignore.iter().any(|pattern| {
+                    if pattern.as_str().contains('/') {
+                        pattern.matches_path_with(ancestor, MATCH_OPTIONS)
+                    } else {
+                        ancestor
+                            .file_name()
+                            .and_then(|name| name.to_str())
+                            .is_some_and(|name| pattern.matches_with(name, MATCH_OPTIONS))
+                    }
+                })
+            })
+    }
+}
+
+/// Overrides of [`ReplicaSettings`] for roots matching a pattern.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RootOverride {
+    pub debounce: Option<Duration>,
+    pub ignore: Option<Vec<Pattern>>,
+    pub hooks: Option<Vec<Hook>>,
+}
+
+/// Settings of replicas, overridable by root.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReplicaConfig {
+    pub defaults: ReplicaSettings,
+    pub roots: Vec<(Pattern, RootOverride)>,
+}
+
+impl ReplicaConfig {
+    /// Settings of a replica with root. All sections matching root apply, the ones with longer
+    /// patterns, i.e., usually more specific, last.
+    pub fn settings(&self, root: &Path) -> ReplicaSettings {
+        let mut matching: Vec<&(Pattern, RootOverride)> = self
+            .roots
+            .iter()
+            .filter(|(pattern, _)| pattern.matches_path_with(root, MATCH_OPTIONS))
+            .collect();
+        matching.sort_by_key(|(pattern, _)| pattern.as_str().len());
+
+        let mut settings = self.defaults.clone();
+        for (_, overrides) in matching {
+            if let Some(debounce) = overrides.debounce {
+                settings.debounce = debounce;
+            }
+            if let Some(ignore) = &overrides.ignore {
+                settings.ignore = ignore.clone();
+            }
+            if let Some(hooks) = &overrides.hooks {
+                settings.hooks = hooks.clone();
+            }
+        }
+        settings
+    }
+}
+
+/// Validated contents of the config file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    pub backend: BackendOptions,
+    pub normalization: Normalization,
+    pub ignore_case: bool,
+    /// File to write logs to instead of stderr.
+    pub log_file: Option<PathBuf>,
+    pub keepalive_interval: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    /// Number of hooks running at the same time.
+    pub hook_concurrency: Option<usize>,
+    pub replicas: ReplicaConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    backend: Option<String>,
+    normalization: Option<String>,
+    ignore_case: Option<bool>,
+    log_file: Option<PathBuf>,
+    debounce: Option<f64>,
+    ignore: Option<Vec<String>>,
+    hooks: Option<Vec<RawHook>>,
+    limits: RawLimits,
+    roots: BTreeMap<String, RawRoot>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawLimits {
+    poll_interval: Option<f64>,
+    poll_batch: Option<usize>,
+    keepalive_interval: Option<f64>,
+    idle_timeout: Option<f64>,
+    hook_concurrency: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawRoot {
+    debounce: Option<f64>,
+    ignore: Option<Vec<String>>,
+    hooks: Option<Vec<RawHook>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHook {
+    command: String,
+    on: Option<String>,
+    timeout: Option<f64>,
+}
+
+fn seconds(name: &str, secs: f64) -> Fallible<Duration> {
+    Ok(Duration::try_from_secs_f64(secs)
+        .with_context(|e| format!("Invalid {} {}: {}", name, secs, e))?)
+}
+
+fn patterns(patterns: &[String]) -> Fallible<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Ok(Pattern::new(pattern)
+                .with_context(|e| format!("Invalid ignore pattern {:?}: {}", pattern, e))?)
+        })
+        .collect()
+}
+
+fn hooks(hooks: &[RawHook]) -> Fallible<Vec<Hook>> {
+    hooks
+        .iter()
+        .map(|hook| {
+            let on = match &hook.on {
+                Some(on) => on.parse()?,
+                None => Default::default(),
+            };
+            let timeout = match hook.timeout {
+                Some(secs) => seconds("timeout", secs)?,
+                None => DEFAULT_TIMEOUT,
+            };
+            if hook.command.trim().is_empty() {
+                bail!("Invalid hook, command is empty");
+            }
+            Ok(Hook {
+                command: hook.command.clone(),
+                on,
+                timeout,
+            })
+        })
+        .collect()
+}
+
+impl Config {
+    /// Path of the config file to read, the one in [`CONFIG_ENV`], or the default one if it
+    /// exists.
+    pub fn path() -> Option<PathBuf> {
+        match env::var_os(CONFIG_ENV) {
+            Some(path) => Some(PathBuf::from(path)),
+            None => config_path().filter(|path| path.exists()),
+        }
+    }
+
+    pub fn read(path: &Path) -> Fallible<Config> {
+        let contents = fs::read_to_string(path)
+            .with_context(|e| format!("Unable to read config {:?}: {}", path, e))?;
+        Ok(Config::parse(&contents)
+            .with_context(|e| format!("Invalid config {:?}: {}", path, e))?)
+    }
+
+    pub fn parse(contents: &str) -> Fallible<Config> {
+        let raw: RawConfig = toml::from_str(contents)?;
+
+        let mut config = Config::default();
+        if let Some(backend) = raw.backend {
+            config.backend.backend = backend.parse()?;
+        }
+        if let Some(secs) = raw.limits.poll_interval {
+            config.backend.poll_interval = seconds("poll_interval", secs)?;
+            if config.backend.poll_interval.is_zero() {
+                bail!("Invalid poll_interval 0, polls have to be apart");
+            }
+        }
+        if let Some(batch) = raw.limits.poll_batch {
+            if batch == 0 {
+                bail!("Invalid poll_batch 0, at least one file has to be checked per poll");
+            }
+            config.backend.poll_batch = batch;
+        }
+        if let Some(normalization) = raw.normalization {
+            config.normalization = normalization.parse()?;
+        }
+        config.ignore_case = raw.ignore_case.unwrap_or_default();
+        config.log_file = raw.log_file;
+        config.keepalive_interval = raw
+            .limits
+            .keepalive_interval
+            .map(|secs| seconds("keepalive_interval", secs))
+            .transpose()?;
+        config.idle_timeout = raw
+            .limits
+            .idle_timeout
+            .map(|secs| seconds("idle_timeout", secs))
+            .transpose()?;
+        if raw.limits.hook_concurrency == Some(0) {
+            bail!("Invalid hook_concurrency 0, at least one hook has to be able to run");
+        }
+        config.hook_concurrency = raw.limits.hook_concurrency;
+        if let (Some(keepalive_interval), Some(idle_timeout)) =
+            (config.keepalive_interval, config.idle_timeout)
+            && keepalive_interval >= idle_timeout
+        {
+            bail!(
+                "Invalid keepalive_interval {:?}, has to be shorter than idle_timeout {:?}",
+                keepalive_interval,
+                idle_timeout
+            );
+        }
+
+        if let Some(secs) = raw.debounce {
+            config.replicas.defaults.debounce = seconds("debounce", secs)?;
+        }
+        if let Some(ignore) = raw.ignore {
+            config.replicas.defaults.ignore = patterns(&ignore)?;
+        }
+        if let Some(raw_hooks) = raw.hooks {
+            config.replicas.defaults.hooks = hooks(&raw_hooks)?;
+        }
+        for (root, overrides) in raw.roots {
+            if !Path::new(&root).is_absolute() {
+                bail!("Invalid root pattern {:?}, has to be absolute", root);
+            }
+            let pattern = Pattern::new(&root)
+                .with_context(|e| format!("Invalid root pattern {:?}: {}", root, e))?;
+            let overrides = RootOverride {
+                debounce: overrides
+                    .debounce
+                    .map(|secs| seconds("debounce", secs))
+                    .transpose()
+                    .with_context(|e| format!("In root {:?}: {}", root, e))?,
+                ignore: overrides
+                    .ignore
+                    .as_deref()
+                    .map(patterns)
+                    .transpose()
+                    .with_context(|e| format!("In root {:?}: {}", root, e))?,
+                hooks: overrides
+                    .hooks
+                    .as_deref()
+                    .map(hooks)
+                    .transpose()
+                    .with_context(|e| format!("In root {:?}: {}", root, e))?,
+            };
+            config.replicas.roots.push((pattern, overrides));
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hooks::Trigger;
+    use crate::watch::Backend;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+backend = "poll"
+normalization = "nfd"
+debounce = 0.5
+ignore = ["*.swp", "build/out"]
+
+[limits]
+poll_batch = 10
+idle_timeout = 120
+
+[roots."/home/*/src"]
+debounce = 2
+
+[roots."/home/*/src/**"]
+ignore = ["target"]
+
+[[roots."/home/*/src/**".hooks]]
+command = "make reload"
+on = "settled"
+timeout = 5
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.backend.backend, Backend::Poll);
+        assert_eq!(config.backend.poll_batch, 10);
+        assert_eq!(config.normalization, Normalization::Nfd);
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(120)));
+        assert_eq!(config.keepalive_interval, None);
+
+        let settings = config.replicas.settings(Path::new("/tmp/sample"));
+        assert_eq!(settings.debounce, Duration::from_millis(500));
+        assert!(settings.is_ignored(Path::new("a/.file.swp")));
+        assert!(settings.is_ignored(Path::new("build/out/file")));
+        assert!(!settings.is_ignored(Path::new("a/build/out")));
+        assert!(!settings.is_ignored(Path::new("")));
+
+        let settings = config.replicas.settings(Path::new("/home/user/src"));
+        assert_eq!(settings.debounce, Duration::from_secs(2));
+        assert!(settings.is_ignored(Path::new("a.swp")));
+
+        let settings = config.replicas.settings(Path::new("/home/user/src/crate"));
+        assert_eq!(settings.debounce, Duration::from_millis(500));
+        assert!(settings.is_ignored(Path::new("target/debug")));
+        assert!(!settings.is_ignored(Path::new("a.swp")));
+        assert_eq!(
+            settings.hooks,
+            [Hook {
+                command: "make reload".into(),
+                on: Trigger::Settled,
+                timeout: Duration::from_secs(5),
+            }]
+        );
+
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_invalid() {
+        for contents in [
+            "backend = \"other\"",
+            "debounce = -1",
+            "unknown = 1",
+            "ignore = [\"[\"]",
+            "[limits]\npoll_batch = 0",
+            "[limits]\npoll_interval = 0",
+            "[limits]\nkeepalive_interval = 90\nidle_timeout = 30",
+            "[roots.\"relative/*\"]",
+            "[roots.\"/tmp/*\"]\ndebounce = \"1\"",
+            "[[hooks]]\ncommand = \"\"",
+            "[[hooks]]\ncommand = \"true\"\non = \"always\"",
+            "[[hooks]]\non = \"changes\"",
+            "[limits]\nhook_concurrency = 0",
+        ] {
+            assert!(Config::parse(contents).is_err(), "{}", contents);
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Control interface of running monitors, for inspecting them outside of unison.
+//!
+//! Every serving process listens on a unix socket of its own in [`control_dir`]. A command is a
+//! single line, answered with a report after which the connection is closed, see
+//! [`Monitor::control`](crate::Monitor::control).
+
+use std::sync::mpsc::Sender;
+
+/// Control command received by a monitor, answered through reply.
+#[derive(Debug)]
+pub struct ControlRequest {
+    pub command: String,
+    pub reply: Sender<String>,
+}
+
+#[cfg(unix)]
+pub use self::socket::*;
+
+#[cfg(unix)]
+mod socket {
+    use super::ControlRequest;
+    use failure::{Fallible, ResultExt, bail};
+    use log::{debug, warn};
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc::{Sender, channel};
+    use std::thread;
+    use std::time::Duration;
+
+    /// How long a client waits for a monitor, which only answers between events.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Directory of the control sockets, in the user's runtime directory.
+    pub fn control_dir() -> PathBuf {
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => PathBuf::from(dir).join("unison-fsmonitor"),
+            None => std::env::temp_dir().join(format!("unison-fsmonitor-{}", uid())),
+        }
+    }
+
+    pub(crate) fn uid() -> u32 {
+        // SAFETY: getuid has no preconditions and never fails.
+        unsafe { libc::getuid() }
+    }
+
+    /// Fails unless dir is a directory only accessible by the current user, as anyone else could
+    /// plant sockets in it posing as monitors, or remove ours.
+    pub(crate) fn check_private(dir: &Path) -> Fallible<()> {
+        let metadata = fs::symlink_metadata(dir)
+            .with_context(|e| format!("Unable to access {:?}: {}", dir, e))?;
+        let uid = uid();
+        let mode = metadata.permissions().mode() & 0o777;
+        if !metadata.is_dir() || metadata.uid() != uid || mode != 0o700 {
+            bail!(
+                "Refusing to use {:?}, it has to be a directory of uid {} with mode 700 (uid {}, mode {:o})",
+                dir,
+                uid,
+                metadata.uid(),
+                mode
+            );
+        }
+        Ok(())
+    }
+
+    /// Control socket path of the process with pid.
+    pub fn control_path(pid: u32) -> PathBuf {
+        control_dir().join(format!("{}.sock", pid))
+    }
+
+    /// Pids and socket paths of the processes listening in [`control_dir`], including ones which
+    /// exited without removing their socket.
+    pub fn control_sockets() -> Fallible<Vec<(u32, PathBuf)>> {
+        let dir = control_dir();
+        let Ok(read_dir) = fs::read_dir(&dir) else {
+            return Ok(vec![]);
+        };
+        check_private(&dir)?;
+        let mut sockets: Vec<(u32, PathBuf)> = read_dir
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                let pid = path.file_stem()?.to_str()?.parse().ok()?;
+                (path.extension()? == "sock").then_some((pid, path))
+            })
+            .collect();
+        sockets.sort();
+        Ok(sockets)
+    }
+
+    /// Control socket, removed when dropped.
+    pub struct ControlSocket {
+        path: PathBuf,
+    }
+
+    impl Drop for ControlSocket {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    /// Creates dir only accessible by the current user, unless it exists, see
+    /// [`check_private`].
+    pub(crate) fn create_private_dir(dir: &Path) -> Fallible<()> {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|e| format!("Unable to create {:?}: {}", dir, e))?;
+        check_private(dir)
+    }
+
+    /// Listens on path in the background, passing commands to tx.
+    pub fn listen(path: &Path, tx: Sender<ControlRequest>) -> Fallible<ControlSocket> {
+        if let Some(dir) = path.parent() {
+            create_private_dir(dir)?;
+        }
+        // Left over by a process with the same pid.
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)
+            .with_context(|e| format!("Unable to bind socket={:?}: {}", path, e))?;
+        debug!("Control socket listening on {:?}", path);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream
+                    .map_err(failure::Error::from)
+                    .and_then(|stream| serve(stream, &tx));
+                if let Err(e) = result {
+                    warn!("Control request failed: {}", e);
+                }
+            }
+        });
+
+        Ok(ControlSocket {
+            path: path.to_owned(),
+        })
+    }
+
+    fn serve(mut stream: UnixStream, tx: &Sender<ControlRequest>) -> Fallible<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let mut command = String::new();
+        BufReader::new(&stream).read_line(&mut command)?;
+
+        let (reply_tx, reply_rx) = channel();
+        tx.send(ControlRequest {
+            command: command.trim().to_owned(),
+            reply: reply_tx,
+        })?;
+        let reply = reply_rx.recv_timeout(TIMEOUT)?;
+        stream.write_all(reply.as_bytes())?;
+        Ok(())
+    }
+
+    /// Sends command to the monitor listening on path, and returns its report.
+    pub fn request(path: &Path, command: &str) -> Fallible<String> {
+        if let Some(dir) = path.parent() {
+            check_private(dir)?;
+        }
+        let mut stream = UnixStream::connect(path)
+            .with_context(|e| format!("Unable to connect to socket={:?}: {}", path, e))?;
+        stream.set_read_timeout(Some(TIMEOUT * 2))?;
+        writeln!(stream, "{}", command)?;
+
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply)?;
+        if reply.is_empty() {
+            bail!("No reply from socket={:?}", path);
+        }
+        Ok(reply)
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_request() {
+            let path = std::env::temp_dir().join(format!(
+                "unison-fsmonitor-control-{}/1.sock",
+                std::process::id()
+            ));
+            let (tx, rx) = channel();
+            let socket = listen(&path, tx).unwrap();
+            thread::spawn(move || {
+                for request in rx {
+                    let _ = request.reply.send(format!("{} done\n", request.command));
+                }
+            });
+
+            assert_eq!(request(&path, "status").unwrap(), "status done\n");
+            drop(socket);
+            assert!(!path.exists());
+            assert!(request(&path, "status").is_err());
+            fs::remove_dir(path.parent().unwrap()).unwrap();
+        }
+
+        #[test]
+        fn test_shared_dir() {
+            let dir = std::env::temp_dir()
+                .join(format!("unison-fsmonitor-shared-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+
+            let path = dir.join("1.sock");
+            let (tx, _rx) = channel();
+            assert!(listen(&path, tx).is_err());
+            assert!(!path.exists());
+            assert!(request(&path, "status").is_err());
+            fs::remove_dir(&dir).unwrap();
+        }
+    }
+}
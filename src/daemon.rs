@@ -0,0 +1,306 @@
+//! Shared daemon serving many unison processes over a unix socket.
+//!
+//! A single daemon owns the watching backend and runs one [`Monitor`] per client session, each
+//! with its own replicas. Watches are shared across sessions with a [`WatchRegistry`], so that
+//! sessions watching the same or overlapping paths don't use up extra kernel watches.
+
+use crate::config::ReplicaConfig;
+use crate::control::{check_private, control_dir, create_private_dir, uid};
+use crate::event::FsEvent;
+use crate::hooks::DEFAULT_CONCURRENCY;
+use crate::monitor::Monitor;
+use crate::normalize::Normalization;
+use crate::registry::WatchRegistry;
+use crate::watch::Watch;
+use failure::{Fallible, ResultExt};
+use log::{debug, error, info, warn};
+use notify::RecursiveMode;
+use std::io::{BufReader, Write, copy, stdin, stdout};
+use std::net::Shutdown;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Environment variable of the daemon socket path. When set, unison-fsmonitor proxies to the
+/// daemon instead of watching by itself.
+pub const SOCKET_ENV: &str = "UNISON_FSMONITOR_SOCKET";
+
+/// Socket path of the daemon, from [`SOCKET_ENV`] if set, or next to the control sockets in
+/// [`control_dir`].
+pub fn socket_path() -> PathBuf {
+    match std::env::var_os(SOCKET_ENV) {
+        Some(path) => path.into(),
+        None => control_dir().join("daemon.sock"),
+    }
+}
+
+/// Watching backend shared by sessions.
+struct Shared<WATCH: Watch> {
+    watcher: WATCH,
+    /// Watches requested by sessions, by session id.
+    registry: WatchRegistry<usize>,
+    next_session: usize,
+}
+
+/// Watches of a single session, backed by the daemon's shared watches. Remaining watches are
+/// released when the session ends.
+pub struct SessionWatch<WATCH: Watch> {
+    shared: Arc<Mutex<Shared<WATCH>>>,
+    session: usize,
+}
+
+impl<WATCH: Watch> Watch for SessionWatch<WATCH> {
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Fallible<()> {
+        let shared = &mut *lock(&self.shared);
+        shared
+            .registry
+            .add(&mut shared.watcher, path, recursive_mode, self.session)
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Fallible<()> {
+        let shared = &mut *lock(&self.shared);
+        shared
+            .registry
+            .remove(&mut shared.watcher, path, &self.session)
+    }
+
+    fn watch_created(&mut self, path: &Path) -> Fallible<()> {
+        lock(&self.shared).watcher.watch_created(path)
+    }
+}
+
+impl<WATCH: Watch> Drop for SessionWatch<WATCH> {
+    fn drop(&mut self) {
+        let shared = &mut *lock(&self.shared);
+        if let Err(e) = shared
+            .registry
+            .remove_dependent(&mut shared.watcher, &self.session)
+        {
+            warn!(
+                "Unable to release watches of session {}: {}",
+                self.session, e
+            );
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Daemon owning the watching backend, serving sessions over unix sockets.
+pub struct Daemon<WATCH: Watch + Send + 'static> {
+    shared: Arc<Mutex<Shared<WATCH>>>,
+    /// Event channels of live sessions.
+    sessions: Arc<Mutex<Vec<Sender<FsEvent>>>>,
+    /// Normalization of the paths reported to sessions.
+    pub normalization: Normalization,
+    /// Match paths regardless of case in sessions, see [`Monitor::ignore_case`].
+    pub ignore_case: bool,
+    /// Settings of replicas by root, see [`Monitor::replica_config`].
+    pub replica_config: ReplicaConfig,
+    /// Number of hooks running at the same time in each session.
+    pub hook_concurrency: usize,
+}
+
+impl<WATCH: Watch + Send + 'static> Daemon<WATCH> {
+    /// Creates a daemon, dispatching events of watcher received from fsevents to all sessions.
+    pub fn new(watcher: WATCH, fsevents: Receiver<FsEvent>) -> Self {
+        let sessions: Arc<Mutex<Vec<Sender<FsEvent>>>> = Arc::default();
+
+        let sessions_clone = sessions.clone();
+        thread::spawn(move || {
+            for event in fsevents {
+                lock(&sessions_clone).retain(|tx| tx.send(event.clone()).is_ok());
+            }
+        });
+
+        Self {
+            shared: Arc::new(Mutex::new(Shared {
+                watcher,
+                registry: WatchRegistry::new(),
+                next_session: 0,
+            })),
+            sessions,
+            normalization: Normalization::None,
+            ignore_case: false,
+            replica_config: ReplicaConfig::default(),
+            hook_concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+
+    /// Accepts sessions on socket, failing only if it can't listen on it.
+    pub fn listen(&self, socket: &Path) -> Fallible<()> {
+        if let Some(dir) = socket.parent()
+            && dir == control_dir()
+        {
+            create_private_dir(dir)?;
+        }
+        if socket.exists() {
+            if UnixStream::connect(socket).is_ok() {
+                failure::bail!("Daemon already listening on {:?}", socket);
+            }
+            // Left over by a previous daemon.
+            std::fs::remove_file(socket)
+                .with_context(|e| format!("Unable to remove socket={:?}: {}", socket, e))?;
+        }
+        let listener = UnixListener::bind(socket)
+            .with_context(|e| format!("Unable to bind socket={:?}: {}", socket, e))?;
+        info!("Listening on {:?}", socket);
+
+        for stream in listener.incoming() {
+            // A failing session mustn't take the others down.
+            let result = stream
+                .map_err(failure::Error::from)
+                .and_then(|stream| self.serve(stream));
+            if let Err(e) = result {
+                warn!("Unable to start session: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Serves a session on stream in the background.
+    pub fn serve(&self, stream: UnixStream) -> Fallible<thread::JoinHandle<()>> {
+        let (tx, rx) = channel();
+        lock(&self.sessions).push(tx);
+
+        let session = {
+            let mut shared = lock(&self.shared);
+            shared.next_session += 1;
+            shared.next_session
+        };
+        let watcher = SessionWatch {
+            shared: self.shared.clone(),
+            session,
+        };
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut monitor = Monitor::new(watcher, stream);
+        monitor.normalization = self.normalization;
+        monitor.ignore_case = self.ignore_case;
+        monitor.replica_config = self.replica_config.clone();
+        monitor.hook_concurrency = self.hook_concurrency;
+
+        Ok(thread::spawn(move || {
+            debug!("Session {} started", session);
+            if let Err(e) = monitor.run(reader, rx) {
+                error!("Session {} failed: {}", session, e);
+            }
+            let _ = monitor.writer.shutdown(Shutdown::Both);
+            debug!("Session {} ended", session);
+        }))
+    }
+}
+
+/// Relays stdin and stdout to the daemon listening on socket.
+pub fn proxy(socket: &Path) -> Fallible<()> {
+    // Anyone else listening on socket could report whatever changes they like.
+    if let Some(dir) = socket.parent()
+        && dir == control_dir()
+    {
+        check_private(dir)?;
+    }
+    let owner = std::fs::symlink_metadata(socket)
+        .with_context(|e| format!("Unable to access socket={:?}: {}", socket, e))?
+        .uid();
+    if owner != uid() {
+        failure::bail!("Refusing socket={:?} of uid {}", socket, owner);
+    }
+    let stream = UnixStream::connect(socket)
+        .with_context(|e| format!("Unable to connect to socket={:?}: {}", socket, e))?;
+
+    let mut writer = stream.try_clone()?;
+    thread::spawn(move || {
+        let _ = copy(&mut stdin(), &mut writer);
+        let _ = writer.shutdown(Shutdown::Write);
+    });
+
+    copy(&mut &stream, &mut stdout().lock())?;
+    stdout().flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::EventKind;
+    use std::io::BufRead;
+
+    /// Records current watches.
+    #[derive(Default)]
+    struct Watcher {
+        watches: Arc<Mutex<Vec<PathBuf>>>,
+    }
+
+    impl Watch for Watcher {
+        fn watch(&mut self, path: &Path, _recursive_mode: RecursiveMode) -> Fallible<()> {
+            lock(&self.watches).push(path.to_owned());
+            Ok(())
+        }
+
+        fn unwatch(&mut self, path: &Path) -> Fallible<()> {
+            lock(&self.watches).retain(|p| p != path);
+            Ok(())
+        }
+    }
+
+    fn request(stream: &mut BufReader<UnixStream>, request: &str, replies: usize) -> Vec<String> {
+        writeln!(stream.get_mut(), "{}", request).unwrap();
+        (0..replies)
+            .map(|_| {
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                line.trim_end().to_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_shared_watches() {
+        let watcher = Watcher::default();
+        let watches = watcher.watches.clone();
+        let (fsevent_tx, fsevent_rx) = channel();
+        let daemon = Daemon::new(watcher, fsevent_rx);
+
+        let (client1, server1) = UnixStream::pair().unwrap();
+        let (client2, server2) = UnixStream::pair().unwrap();
+        let session1 = daemon.serve(server1).unwrap();
+        let session2 = daemon.serve(server2).unwrap();
+        let mut client1 = BufReader::new(client1);
+        let mut client2 = BufReader::new(client2);
+
+        assert_eq!(request(&mut client1, "START 1 %2Ftmp%2Fsample", 1), ["OK"]);
+        assert_eq!(request(&mut client2, "START 2 %2Ftmp%2Fsample", 1), ["OK"]);
+        assert_eq!(*lock(&watches), [PathBuf::from("/tmp/sample")]);
+
+        fsevent_tx
+            .send(FsEvent::new(
+                EventKind::Create,
+                &PathBuf::from("/tmp/sample/filename"),
+            ))
+            .unwrap();
+        // Replicas with pending changes are reported either on WAIT or on the event.
+        assert_eq!(request(&mut client1, "WAIT 1", 1), ["CHANGES 1"]);
+        assert_eq!(request(&mut client2, "WAIT 2", 1), ["CHANGES 2"]);
+        assert_eq!(
+            request(&mut client1, "CHANGES 1", 2),
+            ["RECURSIVE filename", "DONE"]
+        );
+        assert_eq!(
+            request(&mut client2, "CHANGES 2", 2),
+            ["RECURSIVE filename", "DONE"]
+        );
+
+        client1.get_ref().shutdown(Shutdown::Both).unwrap();
+        session1.join().unwrap();
+        assert_eq!(*lock(&watches), [PathBuf::from("/tmp/sample")]);
+
+        client2.get_ref().shutdown(Shutdown::Both).unwrap();
+        session2.join().unwrap();
+        assert!(lock(&watches).is_empty());
+    }
+}
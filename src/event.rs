@@ -0,0 +1,146 @@
+//! Backend-neutral filesystem events.
+//!
+//! Backends translate whatever they observe into [`FsEvent`]s, so that the monitor doesn't depend
+//! on the event model of any particular backend, e.g., the bitflag operations of notify 4.
+
+use log::warn;
+use notify::{Op, RawEvent};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread;
+
+/// What happened to the paths of an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Create,
+    /// Contents changed.
+    ModifyData,
+    /// Permissions, ownership or timestamps changed.
+    ModifyMetadata,
+    Remove,
+    /// Moved away from the path.
+    RenameFrom,
+    /// Moved to the path.
+    RenameTo,
+    /// Events were lost, everything under the paths has to be checked again. No paths means
+    /// everything watched.
+    Rescan,
+}
+
+/// Filesystem event, as reported by a [`Watch`](crate::Watch) backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsEvent {
+    pub kind: EventKind,
+    /// Absolute paths affected, e.g., both sides of a rename when known.
+    pub paths: Vec<PathBuf>,
+    /// Identifies events belonging together, e.g., both halves of a rename.
+    pub tracker: Option<usize>,
+}
+
+impl FsEvent {
+    pub fn new(kind: EventKind, path: &Path) -> Self {
+        Self {
+            kind,
+            paths: vec![path.to_owned()],
+            tracker: None,
+        }
+    }
+
+    /// Everything watched has to be checked again.
+    pub fn rescan_all() -> Self {
+        Self {
+            kind: EventKind::Rescan,
+            paths: vec![],
+            tracker: None,
+        }
+    }
+
+    /// Translates an event of notify 4. Renames are reported without direction, which is guessed
+    /// from whether the path exists now.
+    pub fn from_raw(event: RawEvent) -> Option<Self> {
+        let op = match event.op {
+            Ok(op) => op,
+            Err(e) => {
+                warn!("Error event for {:?}: {}", event.path, e);
+                Op::RESCAN
+            }
+        };
+        let kind = if op.contains(Op::RESCAN) {
+            EventKind::Rescan
+        } else if op.contains(Op::REMOVE) {
+            EventKind::Remove
+        } else if op.contains(Op::RENAME) {
+            match &event.path {
+                Some(path) if path.symlink_metadata().is_ok() => EventKind::RenameTo,
+                _ => EventKind::RenameFrom,
+            }
+        } else if op.contains(Op::CREATE) {
+            EventKind::Create
+        } else if op.intersects(Op::WRITE | Op::CLOSE_WRITE) {
+            EventKind::ModifyData
+        } else if op.contains(Op::CHMOD) {
+            EventKind::ModifyMetadata
+        } else {
+            return None;
+        };
+
+        match (kind, event.path) {
+            (_, Some(path)) => Some(Self {
+                kind,
+                paths: vec![path],
+                tracker: event.cookie.map(|cookie| cookie as usize),
+            }),
+            (EventKind::Rescan, None) => Some(Self::rescan_all()),
+            (_, None) => None,
+        }
+    }
+}
+
+/// Translates events of a notify 4 backend sent to the returned channel, in the background.
+pub fn adapt_raw(tx: Sender<FsEvent>) -> Sender<RawEvent> {
+    let (raw_tx, raw_rx): (Sender<RawEvent>, Receiver<RawEvent>) = channel();
+    thread::spawn(move || {
+        for event in raw_rx {
+            if let Some(event) = FsEvent::from_raw(event)
+                && tx.send(event).is_err()
+            {
+                return;
+            }
+        }
+    });
+    raw_tx
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn raw(path: Option<&str>, op: Op) -> RawEvent {
+        RawEvent {
+            path: path.map(PathBuf::from),
+            op: Ok(op),
+            cookie: Some(7),
+        }
+    }
+
+    #[test]
+    fn test_from_raw() {
+        let event = FsEvent::from_raw(raw(Some("/tmp/sample"), Op::CREATE | Op::WRITE)).unwrap();
+        assert_eq!(event.kind, EventKind::Create);
+        assert_eq!(event.paths, [PathBuf::from("/tmp/sample")]);
+        assert_eq!(event.tracker, Some(7));
+
+        let missing = "/nonexistent/unison-fsmonitor";
+        assert_eq!(
+            FsEvent::from_raw(raw(Some(missing), Op::RENAME))
+                .unwrap()
+                .kind,
+            EventKind::RenameFrom
+        );
+        assert_eq!(
+            FsEvent::from_raw(raw(None, Op::RESCAN)),
+            Some(FsEvent::rescan_all())
+        );
+        assert_eq!(FsEvent::from_raw(raw(None, Op::WRITE)), None);
+    }
+}
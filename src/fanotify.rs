@@ -0,0 +1,729 @@
+//! fanotify backend, watching whole filesystems with a single mark each (Linux 5.9+).
+//!
+//! Recursive inotify needs one watch per directory. With `FAN_REPORT_DFID_NAME`, fanotify reports
+//! the events of a whole filesystem along with a handle of the parent directory and the name of
+//! the entry, which are resolved to paths and filtered down to watched paths. Marking a
+//! filesystem requires `CAP_SYS_ADMIN`, and resolving handles `CAP_DAC_READ_SEARCH`.
+
+use crate::event::{EventKind, FsEvent};
+use crate::mounts::read_mounts;
+use crate::watch::Watch;
+use failure::{Fallible, ResultExt, bail};
+use log::{debug, warn};
+use notify::RecursiveMode;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+// Not exposed by libc yet.
+const FAN_ATTRIB: u64 = 0x0000_0004;
+const FAN_MOVED_FROM: u64 = 0x0000_0040;
+const FAN_MOVED_TO: u64 = 0x0000_0080;
+const FAN_CREATE: u64 = 0x0000_0100;
+const FAN_DELETE: u64 = 0x0000_0200;
+const FAN_DELETE_SELF: u64 = 0x0000_0400;
+const FAN_MOVE_SELF: u64 = 0x0000_0800;
+const FAN_REPORT_DFID_NAME: libc::c_uint = 0x0000_0c00;
+const FAN_EVENT_INFO_TYPE_FID: u8 = 1;
+const FAN_EVENT_INFO_TYPE_DFID_NAME: u8 = 2;
+const FAN_EVENT_INFO_TYPE_DFID: u8 = 3;
+
+/// Events marked filesystems report.
+const MASK: u64 = FAN_CREATE
+    | FAN_DELETE
+    | FAN_MOVED_FROM
+    | FAN_MOVED_TO
+    | libc::FAN_MODIFY
+    | FAN_ATTRIB
+    | FAN_DELETE_SELF
+    | FAN_MOVE_SELF
+    | libc::FAN_ONDIR;
+
+/// Size of `struct fanotify_event_metadata`.
+const METADATA_LEN: usize = 24;
+
+/// How often the reading thread checks whether it should stop, in milliseconds.
+const STOP_CHECK_MS: libc::c_int = 500;
+
+/// Filesystem id, as reported by `statfs`.
+type Fsid = [i32; 2];
+
+/// Event read from fanotify.
+#[derive(Debug, PartialEq)]
+struct Record<'a> {
+    mask: u64,
+    fid: Option<Fid<'a>>,
+}
+
+/// Location of an event: a `struct file_handle` of a directory and the name of an entry in it.
+#[derive(Debug, PartialEq)]
+struct Fid<'a> {
+    fsid: Fsid,
+    handle: &'a [u8],
+    name: &'a OsStr,
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes(
+        buf.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_i32(buf: &[u8], offset: usize) -> Option<i32> {
+    Some(i32::from_ne_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_ne_bytes(
+        buf.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// Parses events read from fanotify.
+fn parse(buf: &[u8]) -> Vec<Record<'_>> {
+    let mut records = vec![];
+    let mut offset = 0;
+    while let (Some(event_len), Some(metadata_len), Some(mask)) = (
+        read_u32(buf, offset),
+        read_u16(buf, offset + 6),
+        read_u64(buf, offset + 8),
+    ) {
+        let (event_len, metadata_len) = (event_len as usize, metadata_len as usize);
+        if event_len < METADATA_LEN || metadata_len > event_len || offset + event_len > buf.len() {
+            warn!("Malformed fanotify event at offset {}", offset);
+            break;
+        }
+        let event = &buf[offset..offset + event_len];
+        records.push(Record {
+            mask,
+            fid: parse_fid(&event[metadata_len..]),
+        });
+        offset += event_len;
+    }
+    records
+}
+
+/// Finds the location among the info records of an event.
+fn parse_fid(mut info: &[u8]) -> Option<Fid<'_>> {
+    while let (Some(&info_type), Some(len)) = (info.first(), read_u16(info, 2)) {
+        let record = info.get(..len as usize)?;
+        if [
+            FAN_EVENT_INFO_TYPE_FID,
+            FAN_EVENT_INFO_TYPE_DFID_NAME,
+            FAN_EVENT_INFO_TYPE_DFID,
+        ]
+        .contains(&info_type)
+        {
+            let fsid = [read_i32(record, 4)?, read_i32(record, 8)?];
+            let handle_bytes = read_u32(record, 12)? as usize;
+            let handle = record.get(12..20 + handle_bytes)?;
+            let name = match info_type {
+                FAN_EVENT_INFO_TYPE_DFID_NAME => {
+                    let name = &record[20 + handle_bytes..];
+                    let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+                    OsStr::from_bytes(&name[..end])
+                }
+                _ => OsStr::new(""),
+            };
+            return Some(Fid { fsid, handle, name });
+        }
+        if len == 0 {
+            break;
+        }
+        info = &info[len as usize..];
+    }
+    None
+}
+
+/// Translates an event mask into an event kind.
+fn kind(mask: u64) -> EventKind {
+    if mask & FAN_CREATE != 0 {
+        EventKind::Create
+    } else if mask & (FAN_DELETE | FAN_DELETE_SELF) != 0 {
+        EventKind::Remove
+    } else if mask & (FAN_MOVED_FROM | FAN_MOVE_SELF) != 0 {
+        EventKind::RenameFrom
+    } else if mask & FAN_MOVED_TO != 0 {
+        EventKind::RenameTo
+    } else if mask & libc::FAN_MODIFY == 0 && mask & FAN_ATTRIB != 0 {
+        EventKind::ModifyMetadata
+    } else {
+        EventKind::ModifyData
+    }
+}
+
+/// Opens the file behind a `struct file_handle` and returns its path.
+fn resolve(mount: &File, handle: &[u8]) -> io::Result<PathBuf> {
+    // The kernel expects an aligned struct file_handle.
+    let mut aligned = vec![0u64; handle.len().div_ceil(8)];
+    // SAFETY: aligned holds at least handle.len() bytes.
+    unsafe {
+        std::ptr::copy_nonoverlapping(handle.as_ptr(), aligned.as_mut_ptr().cast(), handle.len());
+    }
+    // SAFETY: aligned holds a complete struct file_handle, as checked while parsing.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_open_by_handle_at,
+            mount.as_raw_fd(),
+            aligned.as_ptr(),
+            libc::O_PATH | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd was just opened and isn't owned by anything else.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+    fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+}
+
+/// Returns the `struct file_handle` of path.
+fn handle_of(path: &Path) -> io::Result<Vec<u8>> {
+    const MAX_HANDLE_SZ: usize = 128;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut handle = vec![0u64; (8 + MAX_HANDLE_SZ) / 8];
+    // handle_bytes, followed by handle_type.
+    let mut header = [0u8; 8];
+    header[..4].copy_from_slice(&(MAX_HANDLE_SZ as u32).to_ne_bytes());
+    handle[0] = u64::from_ne_bytes(header);
+    let mut mount_id: libc::c_int = 0;
+    // SAFETY: handle has room for MAX_HANDLE_SZ bytes after the header.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_name_to_handle_at,
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            handle.as_mut_ptr(),
+            &mut mount_id,
+            // Like opening path, which the filesystem is marked through.
+            libc::AT_SYMLINK_FOLLOW,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: handle is a plain buffer of at least 8 + MAX_HANDLE_SZ bytes.
+    let bytes: &[u8] =
+        unsafe { std::slice::from_raw_parts(handle.as_ptr().cast(), 8 + MAX_HANDLE_SZ) };
+    let handle_bytes = read_u32(bytes, 0).unwrap_or(0) as usize;
+    Ok(bytes[..8 + handle_bytes].to_vec())
+}
+
+fn fsid_of(file: &File) -> io::Result<Fsid> {
+    // SAFETY: statfs is a plain struct filled by fstatfs.
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fsid_t is two ints.
+    Ok(unsafe { std::mem::transmute::<libc::fsid_t, Fsid>(stat.f_fsid) })
+}
+
+fn mark(fd: &OwnedFd, flags: libc::c_uint, mount: &File) -> io::Result<()> {
+    // SAFETY: a null path marks the filesystem of the open mount fd.
+    let result = unsafe {
+        libc::fanotify_mark(
+            fd.as_raw_fd(),
+            flags | libc::FAN_MARK_FILESYSTEM,
+            MASK,
+            mount.as_raw_fd(),
+            std::ptr::null(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Path watched on a marked filesystem.
+#[derive(Debug)]
+struct Watched {
+    /// Path events are reported as.
+    path: PathBuf,
+    recursive_mode: RecursiveMode,
+    /// Watch it belongs to: path itself, or the watch of a path it's mounted under.
+    watch: PathBuf,
+}
+
+/// Marked filesystem.
+#[derive(Debug)]
+struct Filesystem {
+    /// Any open file on the filesystem, for resolving handles.
+    mount: Arc<File>,
+    /// Watched paths on the filesystem, by the path their handle resolves to. Paths with symbolic
+    /// links or on other bind mounts resolve to another path than they were watched with.
+    paths: BTreeMap<PathBuf, Vec<Watched>>,
+}
+
+impl Filesystem {
+    /// Path resolved from a handle, as seen under the watched paths covering it.
+    fn watched_as(&self, path: &Path) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = path
+            .ancestors()
+            .enumerate()
+            .flat_map(|(depth, base)| {
+                let relative = path.strip_prefix(base).unwrap_or(path);
+                self.paths
+                    .get(base)
+                    .into_iter()
+                    .flatten()
+                    .filter(move |watched| {
+                        watched.recursive_mode == RecursiveMode::Recursive || depth <= 1
+                    })
+                    .map(move |watched| match depth {
+                        0 => watched.path.clone(),
+                        _ => watched.path.join(relative),
+                    })
+            })
+            .collect();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Drops the paths of the watch of path, returning whether there were any.
+    fn remove(&mut self, path: &Path) -> bool {
+        let mut removed = false;
+        self.paths.retain(|_, watched| {
+            let len = watched.len();
+            watched.retain(|watched| watched.watch != path);
+            removed |= watched.len() < len;
+            !watched.is_empty()
+        });
+        removed
+    }
+}
+
+/// Watches paths with fanotify marks on their filesystems, sending events to a channel.
+pub struct FanotifyWatcher {
+    fd: Arc<OwnedFd>,
+    filesystems: Arc<Mutex<HashMap<Fsid, Filesystem>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl FanotifyWatcher {
+    /// Starts reading events in the background. Fails on kernels without `FAN_REPORT_DFID_NAME`,
+    /// and may fail without `CAP_SYS_ADMIN`.
+    pub fn new(tx: Sender<FsEvent>) -> Fallible<Self> {
+        // SAFETY: plain syscall.
+        let fd = unsafe {
+            libc::fanotify_init(
+                libc::FAN_CLASS_NOTIF
+                    | libc::FAN_CLOEXEC
+                    | libc::FAN_NONBLOCK
+                    | FAN_REPORT_DFID_NAME,
+                (libc::O_RDONLY | libc::O_CLOEXEC) as libc::c_uint,
+            )
+        };
+        if fd < 0 {
+            let e = io::Error::last_os_error();
+            bail!("Unable to initialize fanotify: {}", e);
+        }
+        // SAFETY: fd was just opened and isn't owned by anything else.
+        let fd = Arc::new(unsafe { OwnedFd::from_raw_fd(fd) });
+        let filesystems: Arc<Mutex<HashMap<Fsid, Filesystem>>> = Arc::default();
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let fd_clone = fd.clone();
+        let filesystems_clone = filesystems.clone();
+        let stopped_clone = stopped.clone();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; 64 * 1024];
+            while !stopped_clone.load(Ordering::Relaxed) {
+                let mut pollfd = libc::pollfd {
+                    fd: fd_clone.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                // SAFETY: pollfd is valid for the duration of the call.
+                if unsafe { libc::poll(&mut pollfd, 1, STOP_CHECK_MS) } <= 0 {
+                    continue;
+                }
+                // SAFETY: buf is valid for buf.len() bytes.
+                let len =
+                    unsafe { libc::read(fd_clone.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                if len <= 0 {
+                    continue;
+                }
+
+                let records = parse(&buf[..len as usize]);
+                let mounts: Vec<Option<Arc<File>>> = {
+                    let filesystems = filesystems_clone
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner);
+                    records
+                        .iter()
+                        .map(|record| {
+                            let fid = record.fid.as_ref()?;
+                            Some(filesystems.get(&fid.fsid)?.mount.clone())
+                        })
+                        .collect()
+                };
+                // Resolving handles opens files, which would block watching while locked.
+                let located: Vec<(u64, Option<(Fsid, PathBuf)>)> = records
+                    .iter()
+                    .zip(mounts)
+                    .map(|(record, mount)| {
+                        let location = record
+                            .fid
+                            .as_ref()
+                            .zip(mount)
+                            .and_then(|(fid, mount)| Some((fid.fsid, locate(&mount, fid)?)));
+                        (record.mask, location)
+                    })
+                    .collect();
+                let events = {
+                    let filesystems = filesystems_clone
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner);
+                    located
+                        .into_iter()
+                        .filter_map(|(mask, location)| to_event(&filesystems, mask, location))
+                        .collect::<Vec<_>>()
+                };
+                for event in events {
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            fd,
+            filesystems,
+            stopped,
+        })
+    }
+
+    /// Marks the filesystem of path, reporting its events as watched.
+    fn add(
+        &self,
+        filesystems: &mut HashMap<Fsid, Filesystem>,
+        path: &Path,
+        watched: Watched,
+    ) -> Fallible<()> {
+        let mount =
+            File::open(path).with_context(|e| format!("Unable to open path={:?}: {}", path, e))?;
+        let fsid = fsid_of(&mount)
+            .with_context(|e| format!("Unable to stat filesystem of path={:?}: {}", path, e))?;
+
+        let filesystem = match filesystems.entry(fsid) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                mark(&self.fd, libc::FAN_MARK_ADD, &mount).with_context(|e| {
+                    format!("Unable to mark filesystem of path={:?}: {}", path, e)
+                })?;
+                debug!("Marked filesystem of {:?}", path);
+                entry.insert(Filesystem {
+                    mount: Arc::new(mount),
+                    paths: BTreeMap::new(),
+                })
+            }
+        };
+        // Events report the path handles resolve to, which is checked to work before relying
+        // on it.
+        let resolved = handle_of(path)
+            .and_then(|handle| resolve(&filesystem.mount, &handle))
+            .with_context(|e| {
+                format!("Unable to resolve file handles of path={:?}: {}", path, e)
+            })?;
+        if resolved != watched.path {
+            debug!(
+                "Events of {:?} are reported as {:?}",
+                watched.path, resolved
+            );
+        }
+        filesystem.paths.entry(resolved).or_default().push(watched);
+        Ok(())
+    }
+
+    /// Unmarks filesystems without watched paths left.
+    fn unmark_unused(&self, filesystems: &mut HashMap<Fsid, Filesystem>) -> Fallible<()> {
+        let unused: Vec<Fsid> = filesystems
+            .iter()
+            .filter(|(_, filesystem)| filesystem.paths.is_empty())
+            .map(|(fsid, _)| *fsid)
+            .collect();
+        for fsid in unused {
+            if let Some(filesystem) = filesystems.remove(&fsid) {
+                mark(&self.fd, libc::FAN_MARK_REMOVE, &filesystem.mount)
+                    .with_context(|e| format!("Unable to unmark filesystem: {}", e))?;
+                debug!("Unmarked filesystem {:?}", fsid);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resolves the location of a record into a path.
+fn locate(mount: &File, fid: &Fid) -> Option<PathBuf> {
+    let dir = match resolve(mount, fid.handle) {
+        Ok(dir) => dir,
+        Err(e) => {
+            debug!("Unable to resolve fanotify handle: {}", e);
+            return None;
+        }
+    };
+    let dir = match dir.as_os_str().as_bytes().strip_suffix(b" (deleted)") {
+        Some(stripped) if !dir.exists() => PathBuf::from(OsString::from_vec(stripped.to_vec())),
+        _ => dir,
+    };
+    Some(match fid.name.as_bytes() {
+        b"" | b"." => dir,
+        _ => dir.join(fid.name),
+    })
+}
+
+/// Turns a located record into an event of watched paths, if any.
+fn to_event(
+    filesystems: &HashMap<Fsid, Filesystem>,
+    mask: u64,
+    location: Option<(Fsid, PathBuf)>,
+) -> Option<FsEvent> {
+    if mask & libc::FAN_Q_OVERFLOW != 0 {
+        warn!("fanotify queue overflowed, rescanning all watched paths");
+        return Some(FsEvent::rescan_all());
+    }
+
+    let (fsid, path) = location?;
+    let paths = filesystems.get(&fsid)?.watched_as(&path);
+    (!paths.is_empty()).then(|| FsEvent {
+        kind: kind(mask),
+        paths,
+        tracker: None,
+    })
+}
+
+impl Drop for FanotifyWatcher {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Watch for FanotifyWatcher {
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Fallible<()> {
+        let mut targets = vec![(path.to_owned(), path.to_owned())];
+        if recursive_mode == RecursiveMode::Recursive {
+            // A mark covers a single filesystem, those mounted under path are marked as well.
+            let real = fs::canonicalize(path)
+                .with_context(|e| format!("Unable to resolve path={:?}: {}", path, e))?;
+            for mount in read_mounts() {
+                if let Ok(relative) = mount.mount_point.strip_prefix(&real)
+                    && !relative.as_os_str().is_empty()
+                {
+                    targets.push((mount.mount_point.clone(), path.join(relative)));
+                }
+            }
+        }
+
+        let mut filesystems = self
+            .filesystems
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Replaced once marked again, leaving filesystems marked in between.
+        for filesystem in filesystems.values_mut() {
+            filesystem.remove(path);
+        }
+        let result = targets.into_iter().try_for_each(|(target, watched_as)| {
+            let watched = Watched {
+                path: watched_as,
+                recursive_mode,
+                watch: path.to_owned(),
+            };
+            self.add(&mut filesystems, &target, watched)
+        });
+        if result.is_err() {
+            // Left to another backend as a whole rather than missing events under it.
+            for filesystem in filesystems.values_mut() {
+                filesystem.remove(path);
+            }
+        }
+        let unmarked = self.unmark_unused(&mut filesystems);
+        result.and(unmarked)
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Fallible<()> {
+        let mut filesystems = self
+            .filesystems
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut removed = false;
+        for filesystem in filesystems.values_mut() {
+            removed |= filesystem.remove(path);
+        }
+        if !removed {
+            bail!("Path {:?} isn't watched", path);
+        }
+        self.unmark_unused(&mut filesystems)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    /// Lays out an event the way the kernel does.
+    fn event(mask: u64, info: &[u8]) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend(((METADATA_LEN + info.len()) as u32).to_ne_bytes());
+        buf.extend([3, 0]); // vers, reserved
+        buf.extend((METADATA_LEN as u16).to_ne_bytes());
+        buf.extend(mask.to_ne_bytes());
+        buf.extend(libc::FAN_NOFD.to_ne_bytes());
+        buf.extend(1i32.to_ne_bytes()); // pid
+        buf.extend(info);
+        buf
+    }
+
+    fn dfid_name(fsid: Fsid, handle: &[u8], name: &str) -> Vec<u8> {
+        let mut info = vec![FAN_EVENT_INFO_TYPE_DFID_NAME, 0, 0, 0];
+        info.extend(fsid[0].to_ne_bytes());
+        info.extend(fsid[1].to_ne_bytes());
+        info.extend(handle);
+        info.extend(name.as_bytes());
+        info.push(0);
+        info.resize(info.len().next_multiple_of(4), 0);
+        let len = info.len() as u16;
+        info[2..4].copy_from_slice(&len.to_ne_bytes());
+        info
+    }
+
+    #[test]
+    fn test_parse() {
+        // handle_bytes, handle_type, f_handle
+        let handle = [4, 0, 0, 0, 1, 0, 0, 0, 0xa, 0xb, 0xc, 0xd];
+        let mut buf = event(FAN_CREATE, &dfid_name([1, 2], &handle, "filename"));
+        buf.extend(event(libc::FAN_Q_OVERFLOW, &[]));
+
+        assert_eq!(
+            parse(&buf),
+            [
+                Record {
+                    mask: FAN_CREATE,
+                    fid: Some(Fid {
+                        fsid: [1, 2],
+                        handle: &handle,
+                        name: OsStr::new("filename"),
+                    }),
+                },
+                Record {
+                    mask: libc::FAN_Q_OVERFLOW,
+                    fid: None,
+                },
+            ]
+        );
+        // Truncated events are dropped.
+        assert_eq!(parse(&buf[..METADATA_LEN]), []);
+    }
+
+    #[test]
+    fn test_kind() {
+        assert_eq!(kind(FAN_CREATE | libc::FAN_ONDIR), EventKind::Create);
+        assert_eq!(kind(FAN_MOVED_TO), EventKind::RenameTo);
+        assert_eq!(kind(FAN_ATTRIB), EventKind::ModifyMetadata);
+        assert_eq!(kind(FAN_ATTRIB | libc::FAN_MODIFY), EventKind::ModifyData);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_SYS_ADMIN and a temp dir supporting filesystem marks"]
+    fn test_watch() {
+        let (tx, rx) = channel();
+        let mut watcher = FanotifyWatcher::new(tx).unwrap();
+        let root =
+            std::env::temp_dir().join(format!("unison-fsmonitor-fanotify-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let root = root.canonicalize().unwrap();
+        watcher.watch(&root, RecursiveMode::Recursive).unwrap();
+
+        let outside = root.with_extension("outside");
+        fs::write(&outside, "").unwrap();
+        fs::create_dir(root.join("subdir")).unwrap();
+        fs::write(root.join("subdir/filename"), "").unwrap();
+
+        let mut paths = vec![];
+        while let Ok(event) = rx.recv_timeout(Duration::from_secs(2)) {
+            paths.extend(event.paths);
+            if paths.ends_with(&[root.join("subdir/filename")]) {
+                break;
+            }
+        }
+        assert_eq!(paths.first(), Some(&root.join("subdir")));
+        assert!(paths.iter().all(|path| path.starts_with(&root)));
+
+        watcher.unwatch(&root).unwrap();
+        assert!(watcher.filesystems.lock().unwrap().is_empty());
+
+        // Filesystems mounted under the watched path are marked along with it.
+        let mounted = root.join("mounted");
+        fs::create_dir(&mounted).unwrap();
+        let c_mounted = std::ffi::CString::new(mounted.as_os_str().as_bytes()).unwrap();
+        // SAFETY: plain syscalls with valid strings.
+        let result = unsafe {
+            libc::mount(
+                c"none".as_ptr(),
+                c_mounted.as_ptr(),
+                c"tmpfs".as_ptr(),
+                0,
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(result, 0, "{}", io::Error::last_os_error());
+        watcher.watch(&root, RecursiveMode::Recursive).unwrap();
+        assert_eq!(watcher.filesystems.lock().unwrap().len(), 2);
+        fs::write(mounted.join("filename"), "").unwrap();
+        let mut paths = vec![];
+        while let Ok(event) = rx.recv_timeout(Duration::from_secs(2)) {
+            paths.extend(event.paths);
+            if paths.contains(&mounted.join("filename")) {
+                break;
+            }
+        }
+        watcher.unwatch(&root).unwrap();
+        assert!(watcher.filesystems.lock().unwrap().is_empty());
+        assert_eq!(unsafe { libc::umount(c_mounted.as_ptr()) }, 0);
+        assert!(paths.contains(&mounted.join("filename")), "{:?}", paths);
+
+        // Events are reported under the path watched, not where a symbolic link leads to.
+        let link = root.with_extension("link");
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(&root, &link).unwrap();
+        watcher.watch(&link, RecursiveMode::Recursive).unwrap();
+        fs::write(root.join("linked"), "").unwrap();
+        let mut paths = vec![];
+        while let Ok(event) = rx.recv_timeout(Duration::from_secs(2)) {
+            paths.extend(event.paths);
+            if paths.contains(&link.join("linked")) {
+                break;
+            }
+        }
+        assert!(paths.contains(&link.join("linked")), "{:?}", paths);
+        assert!(paths.iter().all(|path| path.starts_with(&link)));
+        watcher.unwatch(&link).unwrap();
+
+        fs::remove_file(&link).unwrap();
+        fs::remove_file(&outside).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
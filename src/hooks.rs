@@ -0,0 +1,348 @@
+//! Commands run on batches of changes of a replica, e.g., for reloading a dev server.
+//!
+//! Hooks run in the background on a limited number of worker threads, so that slow hooks never
+//! hold up unison. A batch for a hook which is still queued is merged into the queued one, and a
+//! hook never runs concurrently with itself for the same replica.
+
+use crate::protocol::Id;
+use failure::{Fallible, ResultExt, bail};
+use log::{debug, warn};
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a hook may run before it is killed, unless configured otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Number of hooks running at the same time, unless configured otherwise.
+pub const DEFAULT_CONCURRENCY: usize = 2;
+
+/// Interval of checking whether a hook exited.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// When a hook runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Trigger {
+    /// With the changes unison fetches with CHANGES.
+    #[default]
+    Changes,
+    /// With the changes since the last batch, as soon as events settled for the debounce of the
+    /// replica, whether unison is waiting or not.
+    Settled,
+}
+
+impl FromStr for Trigger {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Fallible<Trigger> {
+        Ok(match s {
+            "changes" => Trigger::Changes,
+            "settled" => Trigger::Settled,
+            _ => bail!("Unknown hook trigger: {} (expected changes or settled)", s),
+        })
+    }
+}
+
+/// Shell command run in the root of a replica, with changed paths relative to the root on stdin,
+/// one per line. The replica id and root are passed in `UNISON_FSMONITOR_REPLICA` and
+/// `UNISON_FSMONITOR_ROOT`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hook {
+    pub command: String,
+    pub on: Trigger,
+    pub timeout: Duration,
+}
+
+impl Hook {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_owned(),
+            on: Trigger::default(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Runs the hook with paths of replica with root until it exits or times out.
+    pub fn run(&self, replica: &str, root: &Path, paths: &BTreeSet<PathBuf>) -> Fallible<()> {
+        let mut child = shell(&self.command)
+            .current_dir(root)
+            .env("UNISON_FSMONITOR_REPLICA", replica)
+            .env("UNISON_FSMONITOR_ROOT", root)
+            .stdin(Stdio::piped())
+            // Stdout of the monitor is where unison reads replies from.
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|e| format!("Unable to run: {}", e))?;
+
+        let mut input = String::new();
+        for path in paths {
+            if path.as_os_str().is_empty() {
+                input += ".\n";
+            } else {
+                input += &format!("{}\n", path.to_string_lossy());
+            }
+        }
+        if let Some(mut stdin) = child.stdin.take() {
+            // Hooks don't have to read their input.
+            thread::spawn(move || stdin.write_all(input.as_bytes()));
+        }
+        let (stderr_tx, stderr_rx) = channel();
+        if let Some(mut stderr) = child.stderr.take() {
+            thread::spawn(move || {
+                let mut output = String::new();
+                let _ = stderr.read_to_string(&mut output);
+                let _ = stderr_tx.send(output);
+            });
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                kill(&mut child);
+                let _ = child.wait();
+                bail!("Timed out after {:?}", self.timeout);
+            }
+            thread::sleep(EXIT_POLL_INTERVAL);
+        };
+        if !status.success() {
+            // Processes started in the background by the hook may keep stderr open.
+            let stderr = stderr_rx
+                .recv_timeout(Duration::from_millis(100))
+                .unwrap_or_default();
+            bail!("{}: {}", status, stderr.trim());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    use std::os::unix::process::CommandExt;
+    let mut shell = Command::new("sh");
+    // In a process group of its own, for killing whatever the command started along with it.
+    shell.arg("-c").arg(command).process_group(0);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Kills child, started by [`shell`], along with the processes it started.
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    // SAFETY: plain syscall, signalling the process group led by child.
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
+
+struct Job {
+    replica: Id,
+    root: PathBuf,
+    hook: Hook,
+    paths: BTreeSet<PathBuf>,
+}
+
+impl Job {
+    fn key(&self) -> (Id, String) {
+        (self.replica.clone(), self.hook.command.clone())
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: VecDeque<Job>,
+    /// Keys of the jobs being run.
+    running: HashSet<(Id, String)>,
+    workers: usize,
+    idle: usize,
+    closed: bool,
+}
+
+/// Runs hooks on worker threads, started as needed up to a limit.
+pub struct HookRunner {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    concurrency: usize,
+}
+
+fn lock(mutex: &Mutex<Queue>) -> std::sync::MutexGuard<'_, Queue> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl HookRunner {
+    /// Creates a runner running up to concurrency hooks at the same time.
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            queue: Arc::default(),
+            concurrency: concurrency.max(1),
+        }
+    }
+
+    /// Queues hook to run with paths of replica with root.
+    pub fn submit(
+        &self,
+        replica: &Id,
+        root: &Path,
+        hook: &Hook,
+        paths: impl IntoIterator<Item = PathBuf>,
+    ) {
+        let (mutex, condvar) = &*self.queue;
+        let mut queue = lock(mutex);
+        let queued = queue
+            .jobs
+            .iter_mut()
+            .find(|job| job.replica == *replica && job.hook.command == hook.command);
+        match queued {
+            Some(job) => job.paths.extend(paths),
+            None => queue.jobs.push_back(Job {
+                replica: replica.clone(),
+                root: root.to_owned(),
+                hook: hook.clone(),
+                paths: paths.into_iter().collect(),
+            }),
+        }
+
+        if queue.idle == 0 && queue.workers < self.concurrency {
+            queue.workers += 1;
+            let shared = self.queue.clone();
+            thread::spawn(move || work(&shared));
+        }
+        condvar.notify_all();
+    }
+}
+
+impl Drop for HookRunner {
+    /// Lets workers exit once queued hooks ran.
+    fn drop(&mut self) {
+        let (mutex, condvar) = &*self.queue;
+        lock(mutex).closed = true;
+        condvar.notify_all();
+    }
+}
+
+fn work(shared: &(Mutex<Queue>, Condvar)) {
+    let (mutex, condvar) = shared;
+    let mut queue = lock(mutex);
+    loop {
+        let next = queue
+            .jobs
+            .iter()
+            .position(|job| !queue.running.contains(&job.key()));
+        let Some(job) = next.and_then(|idx| queue.jobs.remove(idx)) else {
+            if queue.closed {
+                queue.workers -= 1;
+                return;
+            }
+            queue.idle += 1;
+            queue = condvar.wait(queue).unwrap_or_else(|e| e.into_inner());
+            queue.idle -= 1;
+            continue;
+        };
+        queue.running.insert(job.key());
+        drop(queue);
+
+        debug!(
+            "Running hook {:?} of replica {} with {} path(s)",
+            job.hook.command,
+            job.replica,
+            job.paths.len()
+        );
+        if let Err(e) = job.hook.run(&job.replica, &job.root, &job.paths) {
+            warn!(
+                "Hook {:?} of replica {} failed: {}",
+                job.hook.command, job.replica, e
+            );
+        }
+
+        queue = lock(mutex);
+        queue.running.remove(&job.key());
+        condvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("unison-fsmonitor-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_run() {
+        let root = temp_dir("hook");
+        let paths = BTreeSet::from([PathBuf::new(), PathBuf::from("a/b")]);
+
+        let hook = Hook::new("cat > out; echo \"$UNISON_FSMONITOR_REPLICA\" >> out");
+        hook.run("1", &root, &paths).unwrap();
+        assert_eq!(fs::read_to_string(root.join("out")).unwrap(), ".\na/b\n1\n");
+
+        let error = Hook::new("echo oops >&2; exit 3")
+            .run("1", &root, &paths)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "exit status: 3: oops");
+
+        let hook = Hook {
+            timeout: Duration::from_millis(100),
+            ..Hook::new("sleep 5")
+        };
+        let start = Instant::now();
+        assert!(hook.run("1", &root, &paths).is_err());
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // Processes started by the command go with it.
+        let hook = Hook {
+            timeout: Duration::from_millis(100),
+            ..Hook::new("(sleep 0.5; touch late); true")
+        };
+        assert!(hook.run("1", &root, &paths).is_err());
+        thread::sleep(Duration::from_secs(1));
+        assert!(!root.join("late").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_runner() {
+        let root = temp_dir("runner");
+        let hook = Hook::new("cat >> out");
+        let runner = HookRunner::new(1);
+        runner.submit(&"1".into(), &root, &hook, [PathBuf::from("a")]);
+        runner.submit(&"1".into(), &root, &hook, [PathBuf::from("b")]);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut lines = vec![];
+        while lines.len() < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+            let out = fs::read_to_string(root.join("out")).unwrap_or_default();
+            lines = out.lines().map(String::from).collect();
+        }
+        lines.sort();
+        assert_eq!(lines, ["a", "b"]);
+        drop(runner);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
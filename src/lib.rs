@@ -0,0 +1,47 @@
+//! Implementation of the unison fsmonitor protocol.
+//!
+//! Unison spawns `unison-fsmonitor` and talks to it over stdin/stdout to learn about changes in
+//! its replicas, see
+//! <https://github.com/bcpierce00/unison/blob/master/src/fsmonitor/watchercommon.ml>. This crate
+//! exposes the protocol engine, [`Monitor`], so that it can be embedded with other transports or
+//! watching backends. Backends report [`FsEvent`]s, events of notify 4 are translated with
+//! [`adapt_raw`].
+//!
+//! ```no_run
+//! use std::io::{BufReader, stdin, stdout};
+//! use std::sync::mpsc::channel;
+//! use unison_fsmonitor::{Monitor, adapt_raw};
+//!
+//! let (tx, rx) = channel();
+//! let watcher: notify::RecommendedWatcher = notify::Watcher::new_raw(adapt_raw(tx)).unwrap();
+//! Monitor::new(watcher, stdout()).run(BufReader::new(stdin()), rx).unwrap();
+//! ```
+
+pub mod check;
+pub mod config;
+pub mod control;
+#[cfg(unix)]
+pub mod daemon;
+mod event;
+#[cfg(target_os = "linux")]
+pub mod fanotify;
+pub mod hooks;
+pub mod limits;
+mod monitor;
+pub mod mounts;
+mod normalize;
+pub mod poll;
+pub mod protocol;
+mod registry;
+mod session;
+mod trie;
+mod watch;
+
+pub use crate::event::{EventKind, FsEvent, adapt_raw};
+pub use crate::monitor::{Event, Monitor, ProtocolError};
+pub use crate::normalize::Normalization;
+pub use crate::protocol::{Id, Request, Response};
+pub use crate::registry::WatchRegistry;
+pub use crate::session::{WATCH_REPLICA, WatchSession};
+pub use crate::trie::PathTrie;
+pub use crate::watch::{Backend, BackendOptions, BackendWatcher, Watch};
@@ -0,0 +1,94 @@
+//! Kernel limits of watches.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Path of the per-user limit of inotify watches.
+pub const MAX_USER_WATCHES: &str = "/proc/sys/fs/inotify/max_user_watches";
+
+/// `ENOSPC` on Linux, returned by `inotify_add_watch` once the limit of watches is reached.
+const ENOSPC: i32 = 28;
+
+/// Per-user limit of inotify watches, if available.
+pub fn max_user_watches() -> Option<usize> {
+    fs::read_to_string(MAX_USER_WATCHES)
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Number of inotify watches held by the process of proc dir, e.g., `/proc/self`.
+pub fn watches_in_use(proc_dir: &Path) -> usize {
+    let Ok(entries) = fs::read_dir(proc_dir.join("fdinfo")) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| fs::read_to_string(entry.ok()?.path()).ok())
+        .map(|fdinfo| {
+            fdinfo
+                .lines()
+                .filter(|line| line.starts_with("inotify wd:"))
+                .count()
+        })
+        .sum()
+}
+
+/// Number of inotify watches held by the processes of the current user, the ones counted against
+/// [`max_user_watches`]. Processes of other users can't be inspected and aren't counted.
+pub fn user_watches_in_use() -> usize {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().parse::<u32>().is_ok())
+        .map(|entry| watches_in_use(&entry.path()))
+        .sum()
+}
+
+/// Check if error is caused by running out of watches.
+pub fn is_watch_limit(error: &failure::Error) -> bool {
+    error.iter_chain().any(|fail| {
+        let io_error = match fail.downcast_ref::<notify::Error>() {
+            Some(notify::Error::Io(e)) => Some(e),
+            _ => fail.downcast_ref::<io::Error>(),
+        };
+        io_error.and_then(io::Error::raw_os_error) == Some(ENOSPC)
+    })
+}
+
+/// Explains which limit to raise after failing to watch path.
+pub fn watch_limit_diagnostic(path: &Path) -> String {
+    let limit = match max_user_watches() {
+        Some(limit) => limit.to_string(),
+        None => "unknown".into(),
+    };
+    format!(
+        "Unable to watch {:?}: out of inotify watches, {} in use by this process, \
+         limit fs.inotify.max_user_watches={}. Raise the limit, e.g., \
+         `sudo sysctl fs.inotify.max_user_watches=524288`, \
+         see https://facebook.github.io/watchman/docs/install#system-specific-preparation",
+        path,
+        watches_in_use(Path::new("/proc/self")),
+        limit
+    )
+}
+
+#[test]
+fn test_is_watch_limit() {
+    use failure::ResultExt;
+
+    let error: failure::Error = notify::Error::Io(io::Error::from_raw_os_error(ENOSPC)).into();
+    assert!(is_watch_limit(&error));
+
+    let error: failure::Error = Err::<(), _>(io::Error::from_raw_os_error(ENOSPC))
+        .context("Unable to watch")
+        .unwrap_err()
+        .into();
+    assert!(is_watch_limit(&error));
+
+    let error: failure::Error = notify::Error::PathNotFound.into();
+    assert!(!is_watch_limit(&error));
+}
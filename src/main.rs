@@ -1,657 +1,842 @@
-use failure::{bail, Fallible, ResultExt};
-use log::{debug, error, info};
-use notify::{RawEvent, RecommendedWatcher, RecursiveMode};
-use std::collections::{HashMap, HashSet};
-use std::io::{stdin, stdout, BufRead, Write};
+use failure::{Fallible, ResultExt, bail};
+#[cfg(unix)]
+use log::{debug, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write, stdin, stdout};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::channel;
-use std::thread;
+use std::sync::mpsc::{RecvTimeoutError, channel};
+use std::time::{Duration, Instant};
+use unison_fsmonitor::check::Status;
+use unison_fsmonitor::config::{Config, ReplicaConfig};
+#[cfg(unix)]
+use unison_fsmonitor::control;
+#[cfg(unix)]
+use unison_fsmonitor::daemon::{self, Daemon};
+use unison_fsmonitor::hooks::Hook;
+use unison_fsmonitor::{
+    BackendOptions, BackendWatcher, Event, Monitor, Normalization, Watch, WatchSession,
+};
+
+/// Name, synopsis and description of commands.
+const COMMANDS: &[(&str, &str, &str)] = &[
+    (
+        "serve",
+        "serve [--record <file>]",
+        "Serve the unison fsmonitor protocol on stdin/stdout, the default without a command.
+        If UNISON_FSMONITOR_SOCKET is set, relay to the daemon listening on it instead.
+        With --record, requests are appended to file for replaying them later.",
+    ),
+    (
+        "watch",
+        "watch <root> [--subdir <dir>]... [--follow <link>]... [--json]",
+        "Watch a root outside of unison and print change batches as they happen.",
+    ),
+    (
+        "replay",
+        "replay <file>",
+        "Serve the requests recorded in file by serve --record, then the ones on stdin.",
+    ),
+    (
+        "ctl",
+        "ctl [--pid <pid>] <command>",
+        "Send command to the running monitors, or to the one with pid, and print their replies.
+        Commands: status, the replicas, watches and pending changes of a monitor,
+        pause [<replica>]... and resume [<replica>]..., holding back notifications of changes
+        of replicas, or of all replicas, until resumed.",
+    ),
+    (
+        "check",
+        "check [<root>]",
+        "Validate the config file and options, and print the resulting settings.
+        With root, check watch limits, permissions and filesystem of root, and that events
+        arrive from the backend, failing if changes in root wouldn't be reported.",
+    ),
+    (
+        "daemon",
+        "daemon [--socket <path>]",
+        "Serve many unison processes sharing watches, listening on socket.",
+    ),
+    (
+        "help",
+        "help [<command>]",
+        "Print the usage of all commands, or of command.",
+    ),
+];
+
+const OPTIONS: &str = "\
+Options of all commands:
+    --config <path>
+        Config file to read instead of $XDG_CONFIG_HOME/unison-fsmonitor/config.toml or the
+        path in UNISON_FSMONITOR_CONFIG.
+    --log-file <path>
+        File to append logs to instead of stderr. Levels are set with RUST_LOG.
+    --backend <native|poll|fanotify|auto>
+        Watching backend, auto polls network and FUSE filesystems and uses fanotify where
+        allowed. Defaults to auto.
+    --poll-interval <seconds>
+        Interval between polls. Defaults to 2.
+    --poll-batch <files>
+        Number of files checked for modifications per poll. Defaults to 1000.
+    --debounce <seconds>
+        How long events have to settle before unison is notified. Defaults to 0, roots
+        sections of the config file still apply.
+    --normalization <nfc|nfd|none>
+        Unicode normalization of reported paths and of subdirs from unison. Defaults to none.
+    --ignore-case
+        Match events against roots, subdirs and links regardless of case.
+    -h, --help
+        Print the usage of the command.
+
+Options override settings of the config file.";
+
+fn is_command(name: &str) -> bool {
+    COMMANDS.iter().any(|(command, ..)| *command == name)
+}
 
-fn encode(s: &str) -> impl AsRef<str> {
-    percent_encoding::utf8_percent_encode(s, percent_encoding::NON_ALPHANUMERIC).to_string()
+/// Usage of command, or of all commands.
+fn usage(command: Option<&str>) -> String {
+    let mut usage = String::from("Usage:\n");
+    match command {
+        Some(_) => usage += "    unison-fsmonitor ",
+        None => usage += "    unison-fsmonitor [<command>] [<options>]\n\nCommands:\n    ",
+    }
+    let commands: Vec<String> = COMMANDS
+        .iter()
+        .filter(|(name, ..)| command.is_none_or(|command| command == *name))
+        .map(|(_, synopsis, description)| format!("{}\n        {}\n", synopsis, description))
+        .collect();
+    usage += &commands.join("    ");
+    usage + "\n" + OPTIONS
 }
 
-#[test]
-fn test_encode() {
-    assert_eq!(encode("before%after").as_ref(), "before%25after");
+/// Takes option and its value out of args.
+fn take_value(args: &mut Vec<String>, option: &str) -> Fallible<Option<String>> {
+    let Some(idx) = args.iter().position(|arg| arg == option) else {
+        return Ok(None);
+    };
+    let Some(value) = args.get(idx + 1).cloned() else {
+        bail!("Missing value for {}\n\n{}", option, usage(None));
+    };
+    args.drain(idx..idx + 2);
+    Ok(Some(value))
 }
 
-fn decode<'a>(s: &'a str) -> impl AsRef<str> + 'a {
-    percent_encoding::percent_decode(s.as_bytes()).decode_utf8_lossy()
+fn parse_seconds(name: &str, value: &str) -> Fallible<Duration> {
+    let secs = value
+        .parse::<f64>()
+        .with_context(|e| format!("Invalid {} {:?}: {}", name, value, e))?;
+    Ok(Duration::try_from_secs_f64(secs)
+        .with_context(|e| format!("Invalid {} {:?}: {}", name, value, e))?)
 }
 
-fn parse_input(input: &str) -> Fallible<(String, Vec<String>)> {
-    let mut cmd = String::new();
-    let mut args = vec![];
-    for (idx, word) in input.split_whitespace().enumerate() {
-        if idx == 0 {
-            cmd = word.to_owned();
-        } else {
-            args.push(decode(word).as_ref().to_owned())
+/// Takes options of all commands out of args, overriding options.
+fn take_backend_options(
+    args: &mut Vec<String>,
+    mut options: BackendOptions,
+) -> Fallible<BackendOptions> {
+    let mut idx = 0;
+    while idx < args.len() {
+        let option = args[idx].as_str();
+        if !["--backend", "--poll-interval", "--poll-batch"].contains(&option) {
+            idx += 1;
+            continue;
         }
+        let Some(value) = args.get(idx + 1) else {
+            bail!("Missing value for {}\n\n{}", option, usage(None));
+        };
+        match option {
+            "--backend" => options.backend = value.parse()?,
+            "--poll-interval" => {
+                options.poll_interval = parse_seconds("poll interval", value)?;
+                if options.poll_interval.is_zero() {
+                    bail!("Invalid poll interval 0, polls have to be apart");
+                }
+            }
+            _ => {
+                options.poll_batch = value
+                    .parse::<usize>()
+                    .with_context(|e| format!("Invalid poll batch {:?}: {}", value, e))?
+            }
+        }
+        args.drain(idx..idx + 2);
     }
-    Ok((cmd, args))
+    Ok(options)
 }
 
-#[derive(Debug)]
-enum Event {
-    Input(String),
-    FSEvent(RawEvent),
+/// Settings of the monitor, for all commands.
+#[derive(Debug, Default, PartialEq)]
+struct MonitorOptions {
+    pub normalization: Normalization,
+    pub ignore_case: bool,
+    pub keepalive_interval: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub hook_concurrency: Option<usize>,
+    pub replicas: ReplicaConfig,
 }
 
-trait Watch {
-    fn watch(&mut self, _path: &Path, _recursive_mode: RecursiveMode) -> Fallible<()> {
-        Ok(())
+impl MonitorOptions {
+    pub fn from_config(config: &Config) -> MonitorOptions {
+        MonitorOptions {
+            normalization: config.normalization,
+            ignore_case: config.ignore_case,
+            keepalive_interval: config.keepalive_interval,
+            idle_timeout: config.idle_timeout,
+            hook_concurrency: config.hook_concurrency,
+            replicas: config.replicas.clone(),
+        }
     }
 
-    fn unwatch(&mut self, _path: &Path) -> Fallible<()> {
-        Ok(())
-    }
-}
-
-impl Watch for RecommendedWatcher {
-    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Fallible<()> {
-        Ok(notify::Watcher::watch(self, path, recursive_mode)?)
+    /// Takes options of the monitor out of args, overriding options.
+    pub fn take(args: &mut Vec<String>, mut options: MonitorOptions) -> Fallible<MonitorOptions> {
+        if let Some(idx) = args.iter().position(|arg| arg == "--ignore-case") {
+            options.ignore_case = true;
+            args.remove(idx);
+        }
+        if let Some(value) = take_value(args, "--normalization")? {
+            options.normalization = value.parse()?;
+        }
+        if let Some(value) = take_value(args, "--debounce")? {
+            options.replicas.defaults.debounce = parse_seconds("debounce", &value)?;
+        }
+        Ok(options)
     }
 
-    fn unwatch(&mut self, path: &Path) -> Fallible<()> {
-        Ok(notify::Watcher::unwatch(self, path)?)
+    pub fn apply<WATCH: Watch, WRITE: std::io::Write>(&self, monitor: &mut Monitor<WATCH, WRITE>) {
+        monitor.normalization = self.normalization;
+        monitor.ignore_case = self.ignore_case;
+        if let Some(keepalive_interval) = self.keepalive_interval {
+            monitor.keepalive_interval = keepalive_interval;
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            monitor.idle_timeout = idle_timeout;
+        }
+        if let Some(hook_concurrency) = self.hook_concurrency {
+            monitor.hook_concurrency = hook_concurrency;
+        }
+        monitor.replica_config = self.replicas.clone();
     }
 }
 
-type Id = String;
-
-#[derive(Debug)]
-struct Replica {
+#[derive(Debug, Default, PartialEq)]
+struct WatchOptions {
     pub root: PathBuf,
-    /// Currently being watched paths.
-    pub paths: HashSet<PathBuf>,
-    /// Paths of pending changes. Paths are relative as required by unison.
-    pub pending_changes: HashSet<PathBuf>,
-    /// Whether or not unison is waiting for this replica.
-    pub waited_on: bool,
+    /// Sub-directories to watch instead of the whole root, relative to root.
+    pub subdirs: Vec<String>,
+    /// Symbolic links to follow, relative to root.
+    pub links: Vec<String>,
+    /// Print change batches as JSON lines.
+    pub json: bool,
 }
 
-impl Replica {
-    pub fn new(root: PathBuf) -> Replica {
-        Replica {
-            root,
-            paths: HashSet::new(),
-            pending_changes: HashSet::new(),
-            waited_on: false,
+impl WatchOptions {
+    pub fn parse(args: &[String]) -> Fallible<WatchOptions> {
+        let mut options = WatchOptions::default();
+        let mut root = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--subdir" | "--follow" => {
+                    let value = match args.next() {
+                        Some(value) => value.clone(),
+                        None => bail!("Missing value for {}\n\n{}", arg, usage(Some("watch"))),
+                    };
+                    if arg == "--subdir" {
+                        options.subdirs.push(value);
+                    } else {
+                        options.links.push(value);
+                    }
+                }
+                "--json" => options.json = true,
+                _ if arg.starts_with("--") => {
+                    bail!("Unknown option: {}\n\n{}", arg, usage(Some("watch")))
+                }
+                _ if root.is_none() => root = Some(PathBuf::from(arg)),
+                _ => bail!("Unexpected argument: {}\n\n{}", arg, usage(Some("watch"))),
+            }
         }
-    }
 
-    /// Check if path is being watched in this replica.
-    pub fn is_watching(&self, path: &Path) -> bool {
-        self.paths.iter().any(|base| path.starts_with(base))
+        match root {
+            Some(root) => options.root = root,
+            None => bail!("Missing root\n\n{}", usage(Some("watch"))),
+        }
+        Ok(options)
     }
 }
 
-struct Monitor<WATCH: Watch, WRITE: Write> {
-    pub current_path: PathBuf,
-    pub replicas: HashMap<Id, Replica>,
-    pub link_map: HashMap<PathBuf, HashSet<PathBuf>>,
-    pub watcher: WATCH,
-    pub writer: WRITE,
+fn json_string(s: &str) -> String {
+    let mut output = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => output += "\\\"",
+            '\\' => output += "\\\\",
+            '\n' => output += "\\n",
+            '\r' => output += "\\r",
+            '\t' => output += "\\t",
+            c if c.is_control() => output += &format!("\\u{:04x}", c as u32),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
 }
 
-impl<WATCH: Watch, WRITE: Write> Monitor<WATCH, WRITE> {
-    pub fn new(watcher: WATCH, writer: WRITE) -> Self {
-        Self {
-            current_path: PathBuf::new(),
-            replicas: HashMap::new(),
-            link_map: HashMap::new(),
-            watcher,
-            writer,
+fn print_changes(options: &WatchOptions, changes: &[PathBuf]) {
+    if options.json {
+        let changes: Vec<String> = changes
+            .iter()
+            .map(|c| json_string(&c.to_string_lossy()))
+            .collect();
+        println!(
+            "{{\"root\":{},\"changes\":[{}]}}",
+            json_string(&options.root.to_string_lossy()),
+            changes.join(",")
+        );
+    } else {
+        println!("{} change(s) in {}:", changes.len(), options.root.display());
+        for change in changes {
+            if change.as_os_str().is_empty() {
+                println!("    .");
+            } else {
+                println!("    {}", change.display());
+            }
         }
     }
+}
 
-    pub fn is_watching(&self, path: &Path) -> bool {
-        self.replicas
-            .values()
-            .any(|replica| replica.is_watching(path))
-    }
+fn watch(args: &[String], backend: &BackendOptions, settings: &MonitorOptions) -> Fallible<()> {
+    let mut options = WatchOptions::parse(args)?;
+    // Events are reported with absolute paths.
+    options.root = options
+        .root
+        .canonicalize()
+        .with_context(|e| format!("Unable to canonicalize root={:?}: {}", options.root, e))?;
 
-    pub fn handle_event(&mut self, event: Event) -> Fallible<()> {
-        debug!("event: {:?}", event);
+    let (fsevent_tx, fsevent_rx) = channel();
+    let watcher = BackendWatcher::new(backend, fsevent_tx)?;
+    let mut monitor = Monitor::new(watcher, vec![]);
+    settings.apply(&mut monitor);
+    let mut session =
+        WatchSession::start_with(monitor, &options.root, &options.subdirs, &options.links)?;
+
+    loop {
+        let event = match fsevent_rx.recv_timeout(session.monitor.next_tick(Instant::now())) {
+            Ok(event) => Event::FSEvent(event),
+            Err(RecvTimeoutError::Timeout) => Event::Tick(Instant::now()),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        let mut changes = session.handle_event(event)?;
+        // Also when due amid a stream of events.
+        let now = Instant::now();
+        if session.monitor.next_tick(now).is_zero() {
+            changes.extend(session.handle_event(Event::Tick(now))?);
+        }
+        if !changes.is_empty() {
+            print_changes(&options, &changes);
+        }
+    }
+}
 
-        match event {
-            Event::Input(input) => {
-                let (cmd, args) = parse_input(&input)?;
+/// Reader copying what it reads to a file.
+struct Recorder<READ: Read> {
+    reader: READ,
+    file: File,
+}
 
-                let cmd_str = cmd.as_str();
+impl<READ: Read> Read for Recorder<READ> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.file.write_all(&buf[..len])?;
+        Ok(len)
+    }
+}
 
-                if cmd_str != "WAIT" {
-                    for replica in self.replicas.values_mut() {
-                        replica.waited_on = false;
-                    }
-                }
+fn serve(args: &[String], backend: &BackendOptions, settings: &MonitorOptions) -> Fallible<()> {
+    let record = match args {
+        [] => None,
+        [option, path] if option == "--record" => Some(PathBuf::from(path)),
+        _ => bail!(
+            "Unexpected arguments: {:?}\n\n{}",
+            args,
+            usage(Some("serve"))
+        ),
+    };
+
+    #[cfg(unix)]
+    if record.is_none() && std::env::var_os(daemon::SOCKET_ENV).is_some() {
+        let socket = daemon::socket_path();
+        match daemon::proxy(&socket) {
+            Ok(()) => return Ok(()),
+            Err(e) => warn!("Unable to proxy to daemon, watching by itself: {}", e),
+        }
+    }
 
-                match cmd_str {
-                    "VERSION" => {
-                        let version = &args[0];
-                        if version != "1" {
-                            bail!("Unexpected version: {:?}", version);
-                        }
+    let reader: Box<dyn Read + Send> = match record {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|e| format!("Unable to open record file {:?}: {}", path, e))?;
+            Box::new(Recorder {
+                reader: stdin(),
+                file,
+            })
+        }
+        None => Box::new(stdin()),
+    };
+    run(BufReader::new(reader), backend, settings)
+}
 
-                        self.send_cmd("VERSION", &["1"]);
-                    }
-                    "START" => {
-                        // Start or append watching dirs.
-                        // e.g.,
-                        // START 123 root
-                        // START 123 root subdir
-                        let replica_id = args[0].clone();
-                        let root = PathBuf::from(&args[1]);
-                        self.current_path = root.clone();
-
-                        if let Some(dir) = args.get(2) {
-                            self.current_path = self.current_path.join(dir);
-                        }
-
-                        let replica = self
-                            .replicas
-                            .entry(replica_id)
-                            .or_insert_with(|| Replica::new(root));
-
-                        if !replica.is_watching(&self.current_path) {
-                            self.watcher
-                                .watch(&self.current_path, RecursiveMode::Recursive)?;
-                            replica.paths.insert(self.current_path.clone());
-                        }
-
-                        debug!("replicas: {:?}", self.replicas);
-                        self.send_ack();
-                    }
-                    "DIR" => {
-                        // Add sub-dir to watch list.
-                        self.send_ack();
-                    }
-                    "LINK" => {
-                        // Follow a link.
-                        let path = if let Some(arg) = args.get(0) {
-                            self.current_path.join(arg)
-                        } else {
-                            self.current_path.clone()
-                        };
-                        let realpath = path.canonicalize().with_context(|e| {
-                            format!("Unable to canonicalize path={:?}: {}", path, e)
-                        })?;
-
-                        self.watcher.watch(&realpath, RecursiveMode::Recursive)?;
-                        self.link_map.entry(realpath).or_default().insert(path);
-                        debug!("link_map: {:?}", self.link_map);
-                        self.send_ack();
-                    }
-                    "WAIT" => {
-                        // Start waiting replica.
-                        let replica_id = &args[0];
-                        if let Some(replica) = self.replicas.get_mut(replica_id) {
-                            replica.waited_on = true;
-                            if !replica.pending_changes.is_empty() {
-                                self.send_changes(replica_id);
-                            }
-                        } else {
-                            self.send_error(&format!("Unknown replica: {}", replica_id));
-                        }
-                    }
-                    "CHANGES" => {
-                        // Request pending changes.
-                        let replica_id = &args[0];
-                        let mut changed_paths = HashSet::new();
-                        if let Some(replica) = self.replicas.get_mut(replica_id) {
-                            changed_paths.extend(replica.pending_changes.drain());
-                        }
-                        for p in changed_paths {
-                            self.send_recursive(&p);
-                        }
-                        self.send_done();
-                    }
-                    "RESET" => {
-                        // Stop observing replica.
-                        let replica_id = &args[0];
-                        if let Some(replica) = self.replicas.remove(replica_id) {
-                            for path in &replica.paths {
-                                if !self.is_watching(&path) {
-                                    self.watcher.unwatch(&path)?;
-                                }
-                            }
-                        }
-                        debug!("replicas: {:?}", self.replicas);
-                    }
-                    "DEBUG" | "DONE" => {
-                        // TODO: update debug level.
-                    }
-                    _ => {
-                        self.send_error(&format!("Unrecognized cmd: {}", cmd));
-                    }
-                }
-            }
-            Event::FSEvent(fsevent) => {
-                let mut matched_replica_ids = HashSet::new();
-
-                if let Some(path) = fsevent.path {
-                    let mut paths = vec![path.clone()];
-                    // Get all possible symbolic links for this path.
-                    for (realpath, links) in &self.link_map {
-                        if let Ok(postfix) = path.strip_prefix(realpath) {
-                            for link in links {
-                                paths.push(link.join(postfix));
-                            }
-                        }
-                    }
+fn replay(args: &[String], backend: &BackendOptions, settings: &MonitorOptions) -> Fallible<()> {
+    let [path] = args else {
+        bail!(
+            "Unexpected arguments: {:?}\n\n{}",
+            args,
+            usage(Some("replay"))
+        );
+    };
+    let file =
+        File::open(path).with_context(|e| format!("Unable to open record {:?}: {}", path, e))?;
+    run(BufReader::new(file.chain(stdin())), backend, settings)
+}
 
-                    for (id, replica) in self.replicas.iter_mut() {
-                        for path in &paths {
-                            if let Ok(relative_path) = path.strip_prefix(&replica.root) {
-                                matched_replica_ids.insert(id.clone());
-                                // Unison requires relative path for changes.
-                                replica.pending_changes.insert(relative_path.into());
-                            }
-                        }
-                    }
-                }
+/// Serves requests read from reader on stdout, and commands of the control interface.
+fn run<READ: BufRead + Send + 'static>(
+    reader: READ,
+    backend: &BackendOptions,
+    settings: &MonitorOptions,
+) -> Fallible<()> {
+    let (fsevent_tx, fsevent_rx) = channel();
+    let watcher = BackendWatcher::new(backend, fsevent_tx)?;
+
+    let mut monitor = Monitor::new(watcher, stdout().lock());
+    settings.apply(&mut monitor);
+
+    let (control_tx, control_rx) = channel();
+    #[cfg(unix)]
+    let _socket = control::listen(&control::control_path(std::process::id()), control_tx)
+        .map_err(|e| warn!("Control interface unavailable: {}", e))
+        .ok();
+    #[cfg(not(unix))]
+    drop(control_tx);
+    monitor.run_with_control(reader, fsevent_rx, control_rx)
+}
 
-                if matched_replica_ids.is_empty() {
-                    info!("No replica found for event.")
+#[cfg(unix)]
+fn ctl(args: &[String]) -> Fallible<()> {
+    let mut args = args.to_vec();
+    let pid = take_value(&mut args, "--pid")?
+        .map(|pid| {
+            pid.parse::<u32>()
+                .with_context(|e| format!("Invalid pid {:?}: {}", pid, e))
+        })
+        .transpose()?;
+    if args.is_empty() {
+        bail!("Missing command\n\n{}", usage(Some("ctl")));
+    }
+    let command = args.join(" ");
+
+    let replies = match pid {
+        Some(pid) => vec![(
+            pid,
+            control::request(&control::control_path(pid), &command)?,
+        )],
+        None => control::control_sockets()?
+            .into_iter()
+            .filter_map(|(pid, path)| match control::request(&path, &command) {
+                Ok(reply) => Some((pid, reply)),
+                // Left over by a monitor which didn't exit cleanly.
+                Err(e) => {
+                    debug!("Skipping monitor {}: {}", pid, e);
+                    None
                 }
+            })
+            .collect(),
+    };
+    if replies.is_empty() {
+        bail!("No running monitor found in {:?}", control::control_dir());
+    }
 
-                for id in &matched_replica_ids {
-                    if let Some(replica) = self.replicas.get(id) {
-                        if replica.waited_on {
-                            self.send_changes(id);
-                        }
-                    }
-                }
-            }
+    for (pid, reply) in &replies {
+        if replies.len() > 1 {
+            println!("== {} ==", pid);
         }
-
-        Ok(())
+        print!("{}", reply);
     }
+    if replies.iter().any(|(_, reply)| reply.starts_with("ERROR ")) {
+        bail!("Command {:?} failed", command);
+    }
+    Ok(())
+}
 
-    fn send_cmd(&mut self, cmd: &str, args: &[&str]) {
-        let mut output = cmd.to_owned();
-        for arg in args {
-            output += " ";
-            output += &encode(arg).as_ref();
+/// Effective settings, printed by check.
+fn describe(
+    config_path: Option<&Path>,
+    backend: &BackendOptions,
+    settings: &MonitorOptions,
+) -> String {
+    let seconds = |duration: Option<Duration>| match duration {
+        Some(duration) => format!("{}s", duration.as_secs_f64()),
+        None => "default".to_owned(),
+    };
+    let patterns = |patterns: &[glob::Pattern]| {
+        let patterns: Vec<&str> = patterns.iter().map(glob::Pattern::as_str).collect();
+        format!("[{}]", patterns.join(", "))
+    };
+    let hooks = |hooks: &[Hook], indent: &str| -> String {
+        hooks
+            .iter()
+            .map(|hook| {
+                format!(
+                    "{}hook on {}: {} (timeout {}s)\n",
+                    indent,
+                    format!("{:?}", hook.on).to_lowercase(),
+                    hook.command,
+                    hook.timeout.as_secs_f64()
+                )
+            })
+            .collect()
+    };
+
+    let mut report = match config_path {
+        Some(path) => format!("config: {}\n", path.display()),
+        None => "config: none\n".to_owned(),
+    };
+    report += &format!(
+        "backend: {:?}, polling every {}s, {} files at a time\n",
+        backend.backend,
+        backend.poll_interval.as_secs_f64(),
+        backend.poll_batch
+    );
+    report += &format!("normalization: {:?}\n", settings.normalization);
+    report += &format!("ignore case: {}\n", settings.ignore_case);
+    report += &format!(
+        "keepalive interval: {}\nidle timeout: {}\nhook concurrency: {}\n",
+        seconds(settings.keepalive_interval),
+        seconds(settings.idle_timeout),
+        settings
+            .hook_concurrency
+            .map_or("default".to_owned(), |concurrency| concurrency.to_string())
+    );
+    let defaults = &settings.replicas.defaults;
+    report += &format!(
+        "debounce: {}s\nignore: {}\n",
+        defaults.debounce.as_secs_f64(),
+        patterns(&defaults.ignore)
+    );
+    report += &hooks(&defaults.hooks, "");
+    for (pattern, overrides) in &settings.replicas.roots {
+        report += &format!("root {}:\n", pattern);
+        if let Some(debounce) = overrides.debounce {
+            report += &format!("    debounce: {}s\n", debounce.as_secs_f64());
+        }
+        if let Some(ignore) = &overrides.ignore {
+            report += &format!("    ignore: {}\n", patterns(ignore));
         }
+        if let Some(overrides) = &overrides.hooks {
+            report += &hooks(overrides, "    ");
+        }
+    }
+    report
+}
 
-        debug!(">> {}", output);
-        let _ = writeln!(self.writer, "{}", output);
+fn check(
+    args: &[String],
+    config_path: Option<&Path>,
+    backend: &BackendOptions,
+    settings: &MonitorOptions,
+) -> Fallible<()> {
+    let root = match args {
+        [] => None,
+        [root] if !root.starts_with("--") => Some(PathBuf::from(root)),
+        _ => bail!(
+            "Unexpected arguments: {:?}\n\n{}",
+            args,
+            usage(Some("check"))
+        ),
+    };
+    print!("{}", describe(config_path, backend, settings));
+    let Some(root) = root else {
+        return Ok(());
+    };
+
+    let root = root
+        .canonicalize()
+        .with_context(|e| format!("Unable to canonicalize root={:?}: {}", root, e))?;
+    let replica = settings.replicas.settings(&root);
+    let ignore: Vec<&str> = replica.ignore.iter().map(glob::Pattern::as_str).collect();
+    println!(
+        "\nroot {}: debounce {}s, ignore [{}]",
+        root.display(),
+        replica.debounce.as_secs_f64(),
+        ignore.join(", ")
+    );
+    let findings = unison_fsmonitor::check::check(&root, backend)?;
+    for finding in &findings {
+        println!("{}", finding);
+    }
+    match findings.iter().map(|finding| finding.status).max() {
+        Some(Status::Fail) => bail!("Changes in {:?} wouldn't all be reported", root),
+        _ => Ok(()),
     }
+}
+
+#[cfg(unix)]
+fn daemon(args: &[String], backend: &BackendOptions, settings: &MonitorOptions) -> Fallible<()> {
+    let socket = match args {
+        [] => daemon::socket_path(),
+        [option, path] if option == "--socket" => PathBuf::from(path),
+        _ => bail!(
+            "Unexpected arguments: {:?}\n\n{}",
+            args,
+            usage(Some("daemon"))
+        ),
+    };
 
-    fn send_ack(&mut self) {
-        self.send_cmd("OK", &[]);
+    let (fsevent_tx, fsevent_rx) = channel();
+    let watcher = BackendWatcher::new(backend, fsevent_tx)?;
+    let mut daemon = Daemon::new(watcher, fsevent_rx);
+    daemon.normalization = settings.normalization;
+    daemon.ignore_case = settings.ignore_case;
+    daemon.replica_config = settings.replicas.clone();
+    if let Some(hook_concurrency) = settings.hook_concurrency {
+        daemon.hook_concurrency = hook_concurrency;
     }
+    daemon.listen(&socket)
+}
 
-    fn send_changes(&mut self, replica: &str) {
-        self.send_cmd("CHANGES", &[replica]);
+/// Logs to the log file of config if any, stderr otherwise.
+fn init_logger(config: &Config) -> Fallible<()> {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Some(path) = &config.log_file {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|e| format!("Unable to open log file {:?}: {}", path, e))?;
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
     }
+    builder.init();
+    Ok(())
+}
 
-    fn send_recursive(&mut self, path: &Path) {
-        self.send_cmd("RECURSIVE", &[&path.to_string_lossy()]);
+fn try_main() -> Fallible<()> {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        let command = args.iter().map(String::as_str).find(|arg| is_command(arg));
+        println!("{}", usage(command));
+        return Ok(());
     }
 
-    fn send_done(&mut self) {
-        self.send_cmd("DONE", &[]);
+    let config_path = take_value(&mut args, "--config")?
+        .map(PathBuf::from)
+        .or_else(Config::path);
+    let mut config = match &config_path {
+        Some(path) => Config::read(path)?,
+        None => Config::default(),
+    };
+    if let Some(log_file) = take_value(&mut args, "--log-file")? {
+        config.log_file = Some(log_file.into());
     }
+    init_logger(&config)?;
+
+    let backend = take_backend_options(&mut args, config.backend.clone())?;
+    let settings = MonitorOptions::take(&mut args, MonitorOptions::from_config(&config))?;
+    // Unison spawns the monitor without arguments.
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => ("serve", &[][..]),
+    };
+    match command {
+        "serve" => serve(args, &backend, &settings),
+        "watch" => watch(args, &backend, &settings),
+        "replay" => replay(args, &backend, &settings),
+        #[cfg(unix)]
+        "ctl" => ctl(args),
+        "check" => check(args, config_path.as_deref(), &backend, &settings),
+        #[cfg(unix)]
+        "daemon" => daemon(args, &backend, &settings),
+        "help" => match args {
+            [] => {
+                println!("{}", usage(None));
+                Ok(())
+            }
+            [command] if is_command(command) => {
+                println!("{}", usage(Some(command)));
+                Ok(())
+            }
+            _ => bail!("Unknown command: {}\n\n{}", args.join(" "), usage(None)),
+        },
+        _ => bail!("Unknown command: {}\n\n{}", command, usage(None)),
+    }
+}
 
-    fn send_error(&mut self, msg: &str) {
-        self.send_cmd("ERROR", &[msg]);
+fn main() {
+    if let Err(e) = try_main() {
+        eprintln!("Error: {}", e);
         std::process::exit(1);
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::*;
-    use notify::Op;
-    use std::io::Cursor;
-
-    struct Watcher {}
-
-    impl Watch for Watcher {}
+    use super::*;
+    use unison_fsmonitor::Backend;
 
     #[test]
-    fn test_version() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+    fn test_watch_options() {
+        let args: Vec<String> = vec!["/tmp/sample", "--subdir", "a", "--json", "--follow", "l"]
+            .into_iter()
+            .map(String::from)
+            .collect();
 
-        monitor
-            .handle_event(Event::Input("VERSION 1\n".into()))
-            .unwrap();
-
-        monitor.writer.set_position(0);
         assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec!["VERSION 1"]
+            WatchOptions::parse(&args).unwrap(),
+            WatchOptions {
+                root: PathBuf::from("/tmp/sample"),
+                subdirs: vec!["a".into()],
+                links: vec!["l".into()],
+                json: true,
+            }
         );
+        assert!(WatchOptions::parse(&[]).is_err());
+        assert!(WatchOptions::parse(&["--subdir".into()]).is_err());
+        assert!(WatchOptions::parse(&["a".into(), "b".into()]).is_err());
     }
 
     #[test]
-    fn test_start() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-        let id = "123";
-        let root = PathBuf::from("/tmp/sample");
-
-        monitor
-            .handle_event(Event::Input(format!(
-                "START {} {}\n",
-                id,
-                root.to_string_lossy()
-            )))
-            .unwrap();
-
-        assert_eq!(monitor.replicas.len(), 1);
-        assert!(monitor.replicas.contains_key(id));
-        assert_eq!(monitor.replicas.get(id).unwrap().root, root);
-        assert!(monitor.replicas.get(id).unwrap().paths.contains(&root));
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec!["OK"]
+    fn test_take_backend_options() {
+        let mut args: Vec<String> = vec![
+            "watch",
+            "--backend",
+            "poll",
+            "/tmp/sample",
+            "--poll-interval",
+            "0.5",
+            "--json",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+        let options = take_backend_options(&mut args, BackendOptions::default()).unwrap();
+        assert_eq!(options.backend, Backend::Poll);
+        assert_eq!(options.poll_interval, Duration::from_millis(500));
+        assert_eq!(options.poll_batch, BackendOptions::default().poll_batch);
+        assert_eq!(args, ["watch", "/tmp/sample", "--json"]);
+
+        let defaults = BackendOptions::default;
+        assert!(
+            take_backend_options(&mut vec!["--backend".into(), "other".into()], defaults())
+                .is_err()
         );
-    }
-
-    #[test]
-    fn test_start_with_subdir() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-        let id = "123";
-        let root = PathBuf::from("/tmp/sample");
-        let subdir = PathBuf::from("subdir");
-
-        monitor
-            .handle_event(Event::Input(format!(
-                "START {} {} {}\n",
-                id,
-                root.to_string_lossy(),
-                subdir.to_string_lossy()
-            )))
-            .unwrap();
-
-        assert_eq!(monitor.replicas.len(), 1);
-        assert!(monitor.replicas.contains_key(id));
-        assert_eq!(monitor.replicas.get(id).unwrap().root, root);
-        assert!(monitor
-            .replicas
-            .get(id)
-            .unwrap()
-            .paths
-            .contains(&root.join(&subdir)));
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec!["OK"]
+        assert!(take_backend_options(&mut vec!["--poll-interval".into()], defaults()).is_err());
+        assert!(
+            take_backend_options(&mut vec!["--poll-interval".into(), "0".into()], defaults())
+                .is_err()
         );
     }
 
     #[test]
-    fn test_dir() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-
-        monitor.handle_event(Event::Input("DIR\n".into())).unwrap();
-
-        monitor.writer.set_position(0);
+    fn test_monitor_options() {
+        let mut args: Vec<String> = vec![
+            "watch",
+            "--normalization",
+            "nfc",
+            "/tmp/sample",
+            "--ignore-case",
+            "--debounce",
+            "1.5",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+        let mut replicas = ReplicaConfig::default();
+        replicas.defaults.debounce = Duration::from_millis(1500);
         assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec!["OK"]
+            MonitorOptions::take(&mut args, MonitorOptions::default()).unwrap(),
+            MonitorOptions {
+                normalization: Normalization::Nfc,
+                ignore_case: true,
+                replicas,
+                ..MonitorOptions::default()
+            }
         );
-    }
-
-    #[test]
-    fn test_dir_with_dir() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-
-        monitor
-            .handle_event(Event::Input("DIR dir\n".into()))
-            .unwrap();
-
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec!["OK"]
+        assert_eq!(args, ["watch", "/tmp/sample"]);
+
+        // Options of the config are kept unless overridden.
+        let config = Config::parse("normalization = \"nfd\"\nignore_case = true").unwrap();
+        let options = MonitorOptions::take(&mut args, MonitorOptions::from_config(&config));
+        assert_eq!(options.unwrap(), MonitorOptions::from_config(&config));
+        assert!(
+            MonitorOptions::take(
+                &mut vec!["--normalization".into()],
+                MonitorOptions::default()
+            )
+            .is_err()
         );
     }
 
     #[test]
-    fn test_follow_link() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-        let id = "123";
-        let root = PathBuf::from("/usr/bin");
-        let file = PathBuf::from("env");
-
-        monitor
-            .handle_event(Event::Input(format!(
-                "START {} {} {}\n",
-                id,
-                root.to_string_lossy(),
-                file.to_string_lossy()
-            )))
-            .unwrap();
-        monitor
-            .handle_event(Event::Input(format!("LINK\n",)))
-            .unwrap();
-
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec!["OK", "OK"]
-        );
+    fn test_usage() {
+        let all = usage(None);
+        for (_, synopsis, _) in COMMANDS {
+            assert!(all.contains(synopsis), "{}", synopsis);
+        }
+        let ctl = usage(Some("ctl"));
+        assert!(ctl.starts_with("Usage:\n    unison-fsmonitor ctl [--pid <pid>] <command>\n"));
+        assert!(!ctl.contains("serve"));
+        assert!(ctl.ends_with(OPTIONS));
+        assert!(is_command("replay"));
+        assert!(!is_command("--help"));
     }
 
     #[test]
-    fn test_changes() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-        let id = "123";
-        let root = "/tmp/sample";
-        let filename = "filename";
-
-        monitor
-            .handle_event(Event::Input(format!("START {} {}\n", id, root)))
-            .unwrap();
-        monitor
-            .handle_event(Event::FSEvent(RawEvent {
-                path: Option::Some(PathBuf::from(root).join(filename)),
-                op: Result::Ok(Op::CREATE),
-                cookie: None,
-            }))
-            .unwrap();
-        monitor
-            .handle_event(Event::Input(format!("WAIT {}\n", id)))
-            .unwrap();
-        monitor
-            .handle_event(Event::Input(format!("CHANGES {}\n", id)))
-            .unwrap();
-
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec![
-                "OK",
-                &format!("CHANGES {}", id),
-                &format!("RECURSIVE {}", filename),
-                "DONE"
-            ]
-        );
+    fn test_take_value() {
+        let mut args: Vec<String> = vec!["ctl", "--pid", "12", "status"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        assert_eq!(take_value(&mut args, "--pid").unwrap(), Some("12".into()));
+        assert_eq!(args, ["ctl", "status"]);
+        assert_eq!(take_value(&mut args, "--pid").unwrap(), None);
+        assert!(take_value(&mut vec!["--config".into()], "--config").is_err());
+        assert!(parse_seconds("debounce", "-1").is_err());
+        assert!(parse_seconds("debounce", "soon").is_err());
     }
 
     #[test]
-    fn test_changes_after_wait() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-        let id = "123";
-        let root = "/tmp/sample";
-        let filename = "filename";
-
-        monitor
-            .handle_event(Event::Input(format!("START {} {}\n", id, root)))
-            .unwrap();
-        monitor
-            .handle_event(Event::Input(format!("WAIT {}\n", id)))
-            .unwrap();
-        monitor
-            .handle_event(Event::FSEvent(RawEvent {
-                path: Option::Some(PathBuf::from(root).join(filename)),
-                op: Result::Ok(Op::CREATE),
-                cookie: None,
-            }))
-            .unwrap();
-        monitor
-            .handle_event(Event::Input(format!("CHANGES {}\n", id)))
-            .unwrap();
-
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec![
-                "OK",
-                &format!("CHANGES {}", id),
-                &format!("RECURSIVE {}", filename),
-                "DONE"
-            ]
-        );
+    fn test_recorder() {
+        let path =
+            std::env::temp_dir().join(format!("unison-fsmonitor-record-{}", std::process::id()));
+        let mut recorder = Recorder {
+            reader: "VERSION 1\nWAIT 1\n".as_bytes(),
+            file: File::create(&path).unwrap(),
+        };
+
+        let mut requests = String::new();
+        recorder.read_to_string(&mut requests).unwrap();
+        assert_eq!(requests, "VERSION 1\nWAIT 1\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), requests);
+        std::fs::remove_file(&path).unwrap();
     }
 
     #[test]
-    fn test_changes_with_subdir() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-        let id = "123";
-        let root = "/tmp/sample";
-        let subdir = "subdir";
-        let filename = "filename";
-
-        monitor
-            .handle_event(Event::Input(format!("START {} {} {}\n", id, root, subdir)))
-            .unwrap();
-        monitor
-            .handle_event(Event::FSEvent(RawEvent {
-                path: Option::Some(PathBuf::from(root).join(subdir).join(filename)),
-                op: Result::Ok(Op::CREATE),
-                cookie: None,
-            }))
-            .unwrap();
-        monitor
-            .handle_event(Event::Input(format!("WAIT {}\n", id)))
-            .unwrap();
-        monitor
-            .handle_event(Event::Input(format!("CHANGES {}\n", id)))
-            .unwrap();
-
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec![
-                "OK",
-                &format!("CHANGES {}", id),
-                &format!("RECURSIVE {}%2F{}", subdir, filename),
-                "DONE"
-            ]
+    fn test_describe() {
+        let config = Config::parse("debounce = 2\n[roots.\"/tmp/*\"]\nignore = [\"*.o\"]").unwrap();
+        let report = describe(
+            Some(Path::new("/tmp/config.toml")),
+            &config.backend,
+            &MonitorOptions::from_config(&config),
         );
+        assert!(report.starts_with("config: /tmp/config.toml\nbackend: Auto, polling every 2s"));
+        assert!(report.contains("\ndebounce: 2s\nignore: []\nroot /tmp/*:\n    ignore: [*.o]\n"));
     }
 
     #[test]
-    fn test_changes_no_wait() {
-        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
-        let id = "123";
-        let root = "/tmp/sample";
-        let filename = "filename";
-
-        monitor
-            .handle_event(Event::Input(format!("START {} {}\n", id, root)))
-            .unwrap();
-        monitor
-            .handle_event(Event::FSEvent(RawEvent {
-                path: Option::Some(PathBuf::from(root).join(filename)),
-                op: Result::Ok(Op::CREATE),
-                cookie: None,
-            }))
-            .unwrap();
-
-        monitor.writer.set_position(0);
-        assert_eq!(
-            monitor
-                .writer
-                .lines()
-                .collect::<Result<Vec<String>, _>>()
-                .unwrap(),
-            vec![
-                "OK",
-            ]
-        );
+    fn test_json_string() {
+        assert_eq!(json_string("a\"b\\c\n"), r#""a\"b\\c\n""#);
     }
 }
-
-fn main() -> Fallible<()> {
-    env_logger::init();
-
-    let (fsevent_tx, fsevent_rx) = channel();
-    let watcher: RecommendedWatcher = notify::Watcher::new_raw(fsevent_tx)?;
-
-    let stdout = stdout();
-    let stdout = stdout.lock();
-    let mut monitor = Monitor::new(watcher, stdout);
-
-    let (tx, rx) = channel();
-
-    let tx_clone = tx.clone();
-    thread::spawn(move || -> Fallible<()> {
-        let stdin = stdin();
-        let mut handle = stdin.lock();
-
-        loop {
-            let mut input = String::new();
-            handle.read_line(&mut input)?;
-            tx_clone.send(Event::Input(input))?;
-        }
-    });
-
-    thread::spawn(move || -> Fallible<()> {
-        for event in fsevent_rx {
-            tx.send(Event::FSEvent(event))?;
-        }
-        Ok(())
-    });
-
-    for event in rx {
-        if let Err(e) = monitor.handle_event(event) {
-            error!("Error handling event: {}", e);
-        }
-    }
-
-    Ok(())
-}
@@ -0,0 +1,2442 @@
+//! Protocol engine keeping track of replicas, watches and pending changes.
+
+use crate::config::{ReplicaConfig, ReplicaSettings};
+use crate::control::ControlRequest;
+use crate::event::{EventKind, FsEvent};
+use crate::hooks::{DEFAULT_CONCURRENCY, HookRunner, Trigger};
+use crate::limits::is_watch_limit;
+use crate::mounts::{Mount, changed_mount_points, read_mounts};
+use crate::normalize::{Normalization, fold_case};
+use crate::protocol::{Id, KEEPALIVE, Request, Response, negotiate};
+use crate::registry::WatchRegistry;
+use crate::trie::{PathTrie, skip_components};
+use crate::watch::Watch;
+use failure::{Fail, Fallible, ResultExt};
+use log::{debug, error, info, warn};
+use notify::RecursiveMode;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often [`Monitor::run`] checks keepalives.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Name of the file pausing a replica while it exists in its root, e.g., during a rebase.
+pub const PAUSE_FILE: &str = ".unison-pause";
+
+/// Input of [`Monitor::handle_event`].
+#[derive(Debug)]
+pub enum Event {
+    /// A line received from unison.
+    Input(String),
+    /// An event reported by the watching backend.
+    FSEvent(FsEvent),
+    /// Time passing, used for keepalives.
+    Tick(Instant),
+    /// A command of the control interface.
+    Control(ControlRequest),
+}
+
+/// Error reported to unison with `ERROR`, after which the session can't continue.
+#[derive(Debug)]
+pub struct ProtocolError(pub String);
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Fail for ProtocolError {}
+
+/// What a watch is kept for.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Dependent {
+    Replica(Id),
+    /// A link followed by a replica, by its path.
+    Link(Id, PathBuf),
+    /// An ancestor of a deleted path, watched for its return.
+    Lost(PathBuf),
+}
+
+#[derive(Debug)]
+pub(crate) struct Replica {
+    pub root: PathBuf,
+    /// Currently being watched paths, as matched.
+    pub paths: HashSet<PathBuf>,
+    /// Paths of pending changes. Paths are relative as required by unison.
+    pub pending_changes: HashSet<PathBuf>,
+    /// Paths which couldn't be watched, reported on every CHANGES. Paths are relative.
+    pub always_changed: HashSet<PathBuf>,
+    /// Canonical paths of links followed for the replica, by link.
+    pub links: HashMap<PathBuf, PathBuf>,
+    /// Whether or not unison is waiting for this replica.
+    pub waited_on: bool,
+    /// Settings of the replica, by its root.
+    pub settings: ReplicaSettings,
+    /// When to notify unison of pending changes, once events settled.
+    pub notify_at: Option<Instant>,
+    /// Paths changed since hooks run on settled events last ran. Relative.
+    pub settled_changes: HashSet<PathBuf>,
+    /// Paused with the control interface.
+    pub paused: bool,
+    /// Whether [`PAUSE_FILE`] exists in the root, as of the last event of it or tick.
+    pub pause_file: bool,
+    /// Whether notifications were held back while paused, sent once resumed.
+    pub held: bool,
+}
+
+impl Replica {
+    pub fn new(root: PathBuf, settings: ReplicaSettings) -> Replica {
+        let pause_file = root.join(PAUSE_FILE).exists();
+        Replica {
+            root,
+            paths: HashSet::new(),
+            pending_changes: HashSet::new(),
+            always_changed: HashSet::new(),
+            links: HashMap::new(),
+            waited_on: false,
+            settings,
+            notify_at: None,
+            settled_changes: HashSet::new(),
+            paused: false,
+            pause_file,
+            held: false,
+        }
+    }
+
+    /// Check if the replica is paused, with the control interface or by [`PAUSE_FILE`].
+    pub fn is_paused(&self) -> bool {
+        self.paused || self.pause_file
+    }
+
+    /// Records a change of path, relative to the root.
+    pub fn change(&mut self, path: PathBuf) {
+        if self
+            .settings
+            .hooks
+            .iter()
+            .any(|hook| hook.on == Trigger::Settled)
+        {
+            self.settled_changes.insert(path.clone());
+        }
+        self.pending_changes.insert(path);
+    }
+
+    /// Check if path is being watched in this replica.
+    pub fn is_watching(&self, path: &Path) -> bool {
+        path.ancestors().any(|base| self.paths.contains(base))
+    }
+}
+
+/// Server side of the unison fsmonitor protocol.
+///
+/// Requests from unison and filesystem events are fed in through [`Monitor::handle_event`], or
+/// [`Monitor::run`] which drives both from a reader and a channel. Replies are written to the
+/// writer.
+pub struct Monitor<WATCH: Watch, WRITE: Write> {
+    pub(crate) current_path: PathBuf,
+    /// Replica of the last START, which links are followed for.
+    pub(crate) current_replica: Option<Id>,
+    pub(crate) replicas: HashMap<Id, Replica>,
+    /// Replica ids by root as matched, for routing events.
+    pub(crate) roots: PathTrie<Id>,
+    /// Followed links along with the replica following them, by their canonical paths as
+    /// matched.
+    pub(crate) link_map: PathTrie<(Id, PathBuf)>,
+    /// Followed links along with the replica following them, by their paths and by where they
+    /// are on disk, for noticing when they are re-pointed.
+    pub(crate) link_locations: HashMap<PathBuf, Vec<(Id, PathBuf)>>,
+    pub(crate) registry: WatchRegistry<Dependent>,
+    /// Deleted watched paths, along with the ancestor watched for their return.
+    pub(crate) lost: HashMap<PathBuf, PathBuf>,
+    /// Protocol extensions negotiated with VERSION.
+    pub(crate) capabilities: HashSet<String>,
+    /// Time of the last input from unison.
+    pub(crate) last_input: Instant,
+    /// Time of the last output to unison.
+    pub(crate) last_output: Instant,
+    /// Mount table as of the last check, if checked yet.
+    pub(crate) mounts: Option<Vec<Mount>>,
+    /// Time of the last check of the mount table.
+    pub(crate) last_mount_check: Instant,
+    /// Time of the last [`Event::Tick`].
+    pub(crate) last_tick: Instant,
+    /// Earliest time a replica is to be notified at, as of when it was set. Later events may
+    /// have pushed it back, which the next tick finds out.
+    pub(crate) notify_deadline: Option<Instant>,
+    /// Interval of sending KEEPALIVE to unison when idle, if negotiated.
+    pub keepalive_interval: Duration,
+    /// Shut down after no input from unison for this long, if KEEPALIVE is negotiated.
+    pub idle_timeout: Duration,
+    /// Interval of checking the mount table for filesystems mounted or unmounted under replicas.
+    pub mount_check_interval: Duration,
+    /// Form of names in paths reported to unison, also used to match START subdirs on disk.
+    pub normalization: Normalization,
+    /// Match paths of events against replica roots, subdirs and links regardless of case, e.g.,
+    /// for unison's ignorecase or case-insensitive directories. Changes are reported in the case
+    /// of the events.
+    pub ignore_case: bool,
+    /// Settings of replicas by root, applied when they START.
+    pub replica_config: ReplicaConfig,
+    /// Number of hooks of replicas running at the same time.
+    pub hook_concurrency: usize,
+    /// Runner of hooks, started with the first hook.
+    pub(crate) hooks: Option<HookRunner>,
+    /// Backend of filesystem watches.
+    pub watcher: WATCH,
+    /// Destination of replies to unison.
+    pub writer: WRITE,
+}
+
+/// Path with its parent canonicalized, i.e., where a link is on disk.
+fn canonical_location(path: &Path) -> PathBuf {
+    match (path.parent().map(Path::canonicalize), path.file_name()) {
+        (Some(Ok(parent)), Some(name)) => parent.join(name),
+        _ => path.to_owned(),
+    }
+}
+
+impl<WATCH: Watch, WRITE: Write> Monitor<WATCH, WRITE> {
+    /// Creates a monitor without any replica.
+    pub fn new(watcher: WATCH, writer: WRITE) -> Self {
+        Self {
+            current_path: PathBuf::new(),
+            current_replica: None,
+            replicas: HashMap::new(),
+            roots: PathTrie::new(),
+            link_map: PathTrie::new(),
+            link_locations: HashMap::new(),
+            registry: WatchRegistry::new(),
+            lost: HashMap::new(),
+            capabilities: HashSet::new(),
+            last_input: Instant::now(),
+            last_output: Instant::now(),
+            mounts: None,
+            last_mount_check: Instant::now(),
+            last_tick: Instant::now(),
+            notify_deadline: None,
+            keepalive_interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(90),
+            mount_check_interval: Duration::from_secs(2),
+            normalization: Normalization::None,
+            ignore_case: false,
+            replica_config: ReplicaConfig::default(),
+            hook_concurrency: DEFAULT_CONCURRENCY,
+            hooks: None,
+            watcher,
+            writer,
+        }
+    }
+
+    /// Check if path is being watched by any replica.
+    pub fn is_watching(&self, path: &Path) -> bool {
+        let key = self.key(path);
+        self.replicas
+            .values()
+            .any(|replica| replica.is_watching(&key))
+    }
+
+    /// Path as matched against replica roots, subdirs and links.
+    fn key(&self, path: &Path) -> PathBuf {
+        if self.ignore_case {
+            fold_case(path)
+        } else {
+            path.to_owned()
+        }
+    }
+
+    /// Handles a single request or filesystem event.
+    ///
+    /// Fails with [`ProtocolError`] once an `ERROR` has been sent to unison.
+    pub fn handle_event(&mut self, event: Event) -> Fallible<()> {
+        if let Event::Tick(now) = event {
+            return self.handle_tick(now);
+        }
+
+        debug!("event: {:?}", event);
+
+        match event {
+            Event::Input(input) => {
+                self.last_input = Instant::now();
+
+                let request = match Request::parse(&input) {
+                    Ok(request) => request,
+                    Err(e) => return Err(self.send_error(&e.to_string()).into()),
+                };
+
+                if !matches!(request, Request::Wait { .. } | Request::Keepalive) {
+                    for replica in self.replicas.values_mut() {
+                        replica.waited_on = false;
+                    }
+                }
+
+                match request {
+                    Request::Version {
+                        versions,
+                        capabilities,
+                    } => match negotiate(&versions, &capabilities) {
+                        Some((version, capabilities)) => {
+                            self.capabilities = capabilities.iter().cloned().collect();
+                            self.send(Response::Version {
+                                version,
+                                capabilities,
+                            });
+                        }
+                        None => {
+                            return Err(self
+                                .send_error(&format!("Unsupported versions: {:?}", versions))
+                                .into());
+                        }
+                    },
+                    Request::Start {
+                        replica: replica_id,
+                        root,
+                        subdir,
+                    } => {
+                        // Start or append watching dirs.
+                        // e.g.,
+                        // START 123 root
+                        // START 123 root subdir
+                        self.current_path = root.clone();
+                        self.current_replica = Some(replica_id.clone());
+                        if self.mounts.is_none() {
+                            // Mount changes are relative to when watching started.
+                            self.mounts = Some(read_mounts());
+                        }
+
+                        if let Some(dir) = subdir {
+                            // Unison may name the subdir in another form than it has on disk.
+                            self.current_path = self.normalization.resolve(&root, &dir);
+                        }
+
+                        let root_key = self.key(&root);
+                        let current_key = self.key(&self.current_path);
+                        let settings = self.replica_config.settings(&root);
+                        let roots = &mut self.roots;
+                        let replica =
+                            self.replicas.entry(replica_id.clone()).or_insert_with(|| {
+                                roots.insert(&root_key, replica_id.clone());
+                                Replica::new(root, settings)
+                            });
+
+                        if !replica.is_watching(&current_key) {
+                            let path = self.current_path.clone();
+                            match self.registry.add(
+                                &mut self.watcher,
+                                &path,
+                                RecursiveMode::Recursive,
+                                Dependent::Replica(replica_id.clone()),
+                            ) {
+                                Ok(()) => {
+                                    if let Some(replica) = self.replicas.get_mut(&replica_id) {
+                                        replica.paths.insert(current_key);
+                                    }
+                                }
+                                Err(e) if is_watch_limit(&e) => self.mark_always_changed(&path),
+                                Err(e) => return Err(e),
+                            }
+                        }
+
+                        debug!("replicas: {:?}", self.replicas);
+                        self.send(Response::Ok);
+                    }
+                    Request::Dir(_) => {
+                        // Add sub-dir to watch list.
+                        self.send(Response::Ok);
+                    }
+                    Request::Link(link) => {
+                        // Follow a link.
+                        let Some(replica_id) = self.current_replica.clone() else {
+                            return Err(self.send_error("LINK before START").into());
+                        };
+                        let path = if let Some(link) = link {
+                            self.current_path.join(link)
+                        } else {
+                            self.current_path.clone()
+                        };
+                        let realpath = path.canonicalize().with_context(|e| {
+                            format!("Unable to canonicalize path={:?}: {}", path, e)
+                        })?;
+
+                        // Re-pointing the link only shows in the directory it is in.
+                        let location = canonical_location(&path);
+                        if let Some(parent) = location.parent()
+                            && let Err(e) = self.registry.add(
+                                &mut self.watcher,
+                                parent,
+                                RecursiveMode::NonRecursive,
+                                Dependent::Link(replica_id.clone(), path.clone()),
+                            )
+                        {
+                            warn!("Unable to watch link {:?} for changes: {}", path, e);
+                        }
+                        if self.replicas.contains_key(&replica_id) {
+                            let followed = (replica_id.clone(), path.clone());
+                            for key in [location, path.clone()] {
+                                let links = self.link_locations.entry(key).or_default();
+                                if !links.contains(&followed) {
+                                    links.push(followed.clone());
+                                }
+                            }
+                        }
+
+                        self.follow(&replica_id, &path, &realpath)?;
+                        debug!("replicas: {:?}", self.replicas);
+                        self.send(Response::Ok);
+                    }
+                    Request::Wait {
+                        replica: replica_id,
+                    } => {
+                        // Start waiting replica.
+                        if let Some(replica) = self.replicas.get_mut(&replica_id) {
+                            replica.waited_on = true;
+                            // Otherwise notified once events settled.
+                            if !replica.pending_changes.is_empty() && replica.notify_at.is_none() {
+                                if replica.is_paused() {
+                                    replica.held = true;
+                                } else {
+                                    self.send(Response::Changes {
+                                        replica: replica_id,
+                                    });
+                                }
+                            }
+                        } else {
+                            return Err(self
+                                .send_error(&format!("Unknown replica: {}", replica_id))
+                                .into());
+                        }
+                    }
+                    Request::Changes {
+                        replica: replica_id,
+                    } => {
+                        // Request pending changes.
+                        let mut changed_paths = HashSet::new();
+                        if let Some(replica) = self.replicas.get_mut(&replica_id) {
+                            changed_paths.extend(replica.pending_changes.drain());
+                        }
+                        self.run_hooks(&replica_id, Trigger::Changes, &changed_paths);
+                        if let Some(replica) = self.replicas.get(&replica_id) {
+                            changed_paths.extend(replica.always_changed.iter().cloned());
+                        }
+                        for p in changed_paths {
+                            self.send(Response::Recursive(p));
+                        }
+                        self.send(Response::Done);
+                    }
+                    Request::Reset {
+                        replica: replica_id,
+                    } => {
+                        // Stop observing replica.
+                        if let Some(replica) = self.replicas.remove(&replica_id) {
+                            self.roots.remove(&self.key(&replica.root), &replica_id);
+                            self.registry.remove_dependent(
+                                &mut self.watcher,
+                                &Dependent::Replica(replica_id.clone()),
+                            )?;
+                            // Links followed for the replica go with it.
+                            for (link, target) in &replica.links {
+                                let key = self.key(target);
+                                self.link_map
+                                    .remove(&key, &(replica_id.clone(), link.clone()));
+                            }
+                            let mut links = HashSet::new();
+                            self.link_locations.retain(|_, followed| {
+                                followed.retain(|(owner, link)| {
+                                    if owner != &replica_id {
+                                        return true;
+                                    }
+                                    links.insert(link.clone());
+                                    false
+                                });
+                                !followed.is_empty()
+                            });
+                            for link in links {
+                                self.registry.remove_dependent(
+                                    &mut self.watcher,
+                                    &Dependent::Link(replica_id.clone(), link),
+                                )?;
+                            }
+                            self.remove_unrequested_lost()?;
+                        }
+                        debug!("replicas: {:?}", self.replicas);
+                    }
+                    Request::Keepalive => {
+                        // Only refreshes last input time.
+                    }
+                    Request::Debug | Request::Done => {
+                        // TODO: update debug level.
+                    }
+                }
+            }
+            Event::FSEvent(fsevent) => {
+                let mut matched_replica_ids = HashSet::new();
+
+                if fsevent.kind == EventKind::Rescan && fsevent.paths.is_empty() {
+                    // Events were lost, all replicas have to be scanned again.
+                    for (id, replica) in &mut self.replicas {
+                        matched_replica_ids.insert(id.clone());
+                        replica.change(PathBuf::new());
+                    }
+                }
+
+                let mut event_paths = fsevent.paths;
+                let relinked: Vec<(Id, PathBuf)> = event_paths
+                    .iter()
+                    .filter_map(|path| self.link_locations.get(path))
+                    .flatten()
+                    .cloned()
+                    .collect();
+                for (id, link) in relinked {
+                    self.relink(&id, &link)?;
+                    if !event_paths.contains(&link) {
+                        event_paths.push(link);
+                    }
+                }
+                match fsevent.kind {
+                    EventKind::Create | EventKind::RenameTo => {
+                        for path in event_paths.clone() {
+                            self.handle_created(&path);
+                            self.watch_created(&path);
+                        }
+                    }
+                    EventKind::Remove | EventKind::RenameFrom => {
+                        for path in &event_paths {
+                            self.handle_lost(path);
+                        }
+                    }
+                    _ => {}
+                }
+
+                for path in event_paths {
+                    let mut paths = vec![(None, path.clone())];
+                    // Get all possible symbolic links for this path, which only count for the
+                    // replicas having followed them.
+                    let key = self.key(&path);
+                    for (depth, (id, link)) in self.link_map.prefixes(&key) {
+                        paths.push((Some(id.clone()), link.join(skip_components(&path, depth))));
+                    }
+
+                    for (owner, path) in &paths {
+                        let key = self.key(path);
+                        for (depth, id) in self.roots.prefixes(&key) {
+                            if owner.as_ref().is_some_and(|owner| owner != id) {
+                                continue;
+                            }
+                            if let Some(replica) = self.replicas.get_mut(id) {
+                                // Unison requires relative path for changes.
+                                let relative_path =
+                                    self.normalization.apply(&skip_components(path, depth));
+                                if relative_path == Path::new(PAUSE_FILE) {
+                                    replica.pause_file = replica.root.join(PAUSE_FILE).exists();
+                                    continue;
+                                }
+                                if replica.settings.is_ignored(&relative_path) {
+                                    continue;
+                                }
+                                matched_replica_ids.insert(id.clone());
+                                replica.change(relative_path);
+                            }
+                        }
+                    }
+                }
+
+                if matched_replica_ids.is_empty() {
+                    info!("No replica found for event.")
+                }
+
+                let now = Instant::now();
+                for id in &matched_replica_ids {
+                    let Some(replica) = self.replicas.get_mut(id) else {
+                        continue;
+                    };
+                    if !replica.settings.debounce.is_zero() {
+                        // Notified by handle_tick, unless more events come in until then.
+                        let notify_at = now + replica.settings.debounce;
+                        replica.notify_at = Some(notify_at);
+                        self.notify_deadline = Some(
+                            self.notify_deadline
+                                .map_or(notify_at, |deadline| deadline.min(notify_at)),
+                        );
+                        continue;
+                    }
+                    self.notify(id);
+                }
+            }
+            Event::Control(request) => {
+                let reply = self.control(&request.command);
+                if request.reply.send(reply).is_err() {
+                    debug!("Control request {:?} abandoned", request.command);
+                }
+            }
+            Event::Tick(_) => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    /// Watches target of link for replica id, unless it leads back to link.
+    fn follow(&mut self, id: &Id, link: &Path, target: &Path) -> Fallible<()> {
+        if self.leads_back(id, target, link) {
+            // Unison wouldn't get to the end of it either, a watch would only overlap with the
+            // ones in place.
+            warn!("Not following {:?} to {:?}, a loop", link, target);
+            return Ok(());
+        }
+        // Overlapping watches of targets inside replicas or other links are deduplicated by the
+        // registry.
+        match self.registry.add(
+            &mut self.watcher,
+            target,
+            RecursiveMode::Recursive,
+            Dependent::Link(id.clone(), link.to_owned()),
+        ) {
+            Ok(()) => {
+                let key = self.key(target);
+                if let Some(replica) = self.replicas.get_mut(id) {
+                    replica.links.insert(link.to_owned(), target.to_owned());
+                    self.link_map.insert(&key, (id.clone(), link.to_owned()));
+                }
+            }
+            Err(e) if is_watch_limit(&e) => self.mark_always_changed(link),
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Follows link again after it changed, moving the watch of its target if it was re-pointed.
+    fn relink(&mut self, id: &Id, link: &Path) -> Fallible<()> {
+        let Some(replica) = self.replicas.get(id) else {
+            return Ok(());
+        };
+        let previous = replica.links.get(link).cloned();
+        let realpath = link.canonicalize().ok();
+        if previous == realpath {
+            return Ok(());
+        }
+        info!(
+            "Link {:?} now leads to {:?}, was {:?}",
+            link, realpath, previous
+        );
+
+        if let Some(previous) = previous {
+            let key = self.key(&previous);
+            self.link_map.remove(&key, &(id.clone(), link.to_owned()));
+            if let Some(replica) = self.replicas.get_mut(id) {
+                replica.links.remove(link);
+            }
+            self.registry.remove(
+                &mut self.watcher,
+                &previous,
+                &Dependent::Link(id.clone(), link.to_owned()),
+            )?;
+            self.remove_unrequested_lost()?;
+        }
+        // Dangling links are followed again once they lead somewhere.
+        if let Some(realpath) = realpath {
+            self.follow(id, link, &realpath)?;
+        }
+        debug!("replicas: {:?}", self.replicas);
+        Ok(())
+    }
+
+    /// Check if following link to target, and the links replica id followed under target, leads
+    /// back to link, i.e., if the link is part of a loop.
+    fn leads_back(&self, id: &Id, target: &Path, link: &Path) -> bool {
+        let Some(replica) = self.replicas.get(id) else {
+            return false;
+        };
+        let locations = [self.key(link), self.key(&canonical_location(link))];
+        let mut visited = HashSet::new();
+        let mut pending = vec![self.key(target)];
+        while let Some(dir) = pending.pop() {
+            if !visited.insert(dir.clone()) {
+                continue;
+            }
+            // A link resolving to itself is no link at all.
+            if locations
+                .iter()
+                .any(|location| location != &dir && location.starts_with(&dir))
+            {
+                return true;
+            }
+            for (followed, realpath) in &replica.links {
+                if self.key(followed).starts_with(&dir)
+                    || self.key(&canonical_location(followed)).starts_with(&dir)
+                {
+                    pending.push(self.key(realpath));
+                }
+            }
+        }
+        false
+    }
+
+    /// Check if path is watched for a replica or a link, rather than for the return of a path.
+    fn is_requested(&self, path: &Path) -> bool {
+        self.registry
+            .dependents(path)
+            .any(|dependent| !matches!(dependent, Dependent::Lost(_)))
+    }
+
+    /// Watches the closest existing ancestor of a deleted watched path, for its return. The
+    /// backend watch of path died with it.
+    fn handle_lost(&mut self, path: &Path) {
+        let dependents: Vec<Dependent> = self.registry.dependents(path).cloned().collect();
+        if dependents.is_empty() {
+            return;
+        }
+        self.registry.forget(&mut self.watcher, path);
+
+        for dependent in dependents {
+            if let Dependent::Lost(lost) = dependent {
+                // Watched for the return of another path, look further up.
+                self.lost.remove(&lost);
+                let dependent = Dependent::Lost(lost.clone());
+                if let Err(e) = self.registry.remove(&mut self.watcher, path, &dependent) {
+                    warn!("Unable to unwatch {:?}: {}", path, e);
+                }
+                self.watch_for_return(&lost);
+            }
+        }
+        if self.is_requested(path) {
+            info!("Watched path {:?} went away", path);
+            self.watch_for_return(path);
+        }
+    }
+
+    fn watch_for_return(&mut self, path: &Path) {
+        for ancestor in path.ancestors().skip(1) {
+            if !ancestor.is_dir() {
+                continue;
+            }
+            let dependent = Dependent::Lost(path.to_owned());
+            match self.registry.add(
+                &mut self.watcher,
+                ancestor,
+                RecursiveMode::NonRecursive,
+                dependent,
+            ) {
+                Ok(()) => {
+                    debug!("Watching {:?} for the return of {:?}", ancestor, path);
+                    self.lost.insert(path.to_owned(), ancestor.to_owned());
+                    return;
+                }
+                Err(e) => debug!("Unable to watch {:?}: {}", ancestor, e),
+            }
+        }
+        warn!("Unable to watch for the return of {:?}", path);
+    }
+
+    /// Watches deleted paths again once path, or an ancestor of them, got created.
+    fn handle_created(&mut self, path: &Path) {
+        let returned: Vec<(PathBuf, PathBuf)> = self
+            .lost
+            .iter()
+            .filter(|(lost, _)| lost.starts_with(path))
+            .map(|(lost, ancestor)| (lost.clone(), ancestor.clone()))
+            .collect();
+        for (lost, ancestor) in returned {
+            self.lost.remove(&lost);
+            let dependent = Dependent::Lost(lost.clone());
+            if let Err(e) = self
+                .registry
+                .remove(&mut self.watcher, &ancestor, &dependent)
+            {
+                warn!("Unable to unwatch {:?}: {}", ancestor, e);
+            }
+            if !lost.is_dir() {
+                // Only an ancestor is back so far.
+                self.watch_for_return(&lost);
+                continue;
+            }
+            info!("Watched path {:?} is back", lost);
+            if let Err(e) = self.registry.restore(&mut self.watcher, &lost) {
+                warn!("Unable to watch {:?} again: {}", lost, e);
+                self.watch_for_return(&lost);
+            }
+        }
+    }
+
+    /// Stops waiting for the return of paths no longer requested.
+    fn remove_unrequested_lost(&mut self) -> Fallible<()> {
+        let unrequested: Vec<(PathBuf, PathBuf)> = self
+            .lost
+            .iter()
+            .filter(|(lost, _)| !self.is_requested(lost))
+            .map(|(lost, ancestor)| (lost.clone(), ancestor.clone()))
+            .collect();
+        for (lost, ancestor) in unrequested {
+            self.lost.remove(&lost);
+            self.registry
+                .remove(&mut self.watcher, &ancestor, &Dependent::Lost(lost))?;
+        }
+        Ok(())
+    }
+
+    /// Watches a directory created under a replica. Entries created before the backend got to
+    /// watch it have no events of their own, but unison scans the whole directory as it changed.
+    fn watch_created(&mut self, path: &Path) {
+        let key = self.key(path);
+        let is_replicated =
+            self.roots.contains_prefix_of(&key) || !self.link_map.prefixes(&key).is_empty();
+        if !is_replicated || !path.symlink_metadata().is_ok_and(|m| m.is_dir()) {
+            return;
+        }
+        if let Err(e) = self.watcher.watch_created(path) {
+            warn!("Unable to watch created directory {:?}: {}", path, e);
+        }
+    }
+
+    /// Reports path as changed on every CHANGES from now on, for when it can't be watched.
+    fn mark_always_changed(&mut self, path: &Path) {
+        let matches: Vec<(usize, Id)> = self
+            .roots
+            .prefixes(&self.key(path))
+            .into_iter()
+            .map(|(depth, id)| (depth, id.clone()))
+            .collect();
+        for (depth, id) in matches {
+            if let Some(replica) = self.replicas.get_mut(&id) {
+                let relative_path = self.normalization.apply(&skip_components(path, depth));
+                warn!(
+                    "Reporting {:?} of replica {} as always changed",
+                    relative_path, id
+                );
+                replica.always_changed.insert(relative_path.clone());
+                replica.change(relative_path);
+            }
+        }
+    }
+
+    /// Watches paths affected by filesystems mounted or unmounted since the last check again,
+    /// as their watches went stale or miss the new content, and reports them as changed.
+    pub(crate) fn handle_mounts(&mut self, mounts: Vec<Mount>) -> Fallible<()> {
+        let Some(previous) = self.mounts.replace(mounts) else {
+            return Ok(());
+        };
+        let changed = changed_mount_points(&previous, self.mounts.as_deref().unwrap_or_default());
+        if changed.is_empty() {
+            return Ok(());
+        }
+        debug!("Mounts changed: {:?}", changed);
+
+        let watched: Vec<PathBuf> = self
+            .registry
+            .requested_paths()
+            .filter(|path| self.is_requested(path))
+            .map(Path::to_owned)
+            .collect();
+        let mut rewatch = BTreeSet::new();
+        let mut subtrees = BTreeSet::new();
+        for mount_point in &changed {
+            for path in &watched {
+                if mount_point.starts_with(path) {
+                    rewatch.insert(path.clone());
+                    subtrees.insert(mount_point.clone());
+                } else if path.starts_with(mount_point) {
+                    rewatch.insert(path.clone());
+                    subtrees.insert(path.clone());
+                }
+            }
+        }
+
+        for path in rewatch {
+            info!("Mounts changed under {:?}, watching it again", path);
+            self.registry.forget(&mut self.watcher, &path);
+            if let Err(e) = self.registry.restore(&mut self.watcher, &path) {
+                warn!("Unable to watch {:?} again: {}", path, e);
+                self.watch_for_return(&path);
+            }
+        }
+        for subtree in subtrees {
+            self.handle_event(Event::FSEvent(FsEvent::new(EventKind::Rescan, &subtree)))?;
+        }
+        Ok(())
+    }
+
+    fn handle_tick(&mut self, now: Instant) -> Fallible<()> {
+        self.last_tick = now;
+        let mut settled = vec![];
+        for (id, replica) in &mut self.replicas {
+            if replica.notify_at.is_some_and(|notify_at| notify_at <= now) {
+                replica.notify_at = None;
+                settled.push(id.clone());
+            }
+        }
+        self.notify_deadline = self
+            .replicas
+            .values()
+            .filter_map(|replica| replica.notify_at)
+            .min();
+        for id in settled {
+            self.notify(&id);
+        }
+        // Resumed by removing the pause file, even if its event got lost.
+        let mut held = vec![];
+        for (id, replica) in &mut self.replicas {
+            if replica.pause_file {
+                replica.pause_file = replica.root.join(PAUSE_FILE).exists();
+            }
+            if replica.held {
+                held.push(id.clone());
+            }
+        }
+        for id in held {
+            self.release(&id);
+        }
+
+        if !self.replicas.is_empty()
+            && now.saturating_duration_since(self.last_mount_check) >= self.mount_check_interval
+        {
+            self.last_mount_check = now;
+            self.handle_mounts(read_mounts())?;
+        }
+
+        if !self.capabilities.contains(KEEPALIVE) {
+            return Ok(());
+        }
+
+        if now.saturating_duration_since(self.last_input) >= self.idle_timeout {
+            return Err(self
+                .send_error(&format!(
+                    "No input from unison for {:?}, shutting down",
+                    self.idle_timeout
+                ))
+                .into());
+        }
+        if now.saturating_duration_since(self.last_output) >= self.keepalive_interval {
+            self.send(Response::Keepalive);
+            self.last_output = now;
+        }
+
+        Ok(())
+    }
+
+    /// Notifies unison of the pending changes of replica if it waits, and runs hooks for settled
+    /// events, unless the replica is paused.
+    fn notify(&mut self, id: &Id) {
+        let Some(replica) = self.replicas.get_mut(id) else {
+            return;
+        };
+        if replica.is_paused() {
+            if !replica.held {
+                info!("Holding back changes of paused replica {}", id);
+            }
+            replica.held = true;
+            return;
+        }
+        if replica.waited_on && !replica.pending_changes.is_empty() {
+            self.send(Response::Changes {
+                replica: id.clone(),
+            });
+        }
+        self.run_settled_hooks(id);
+    }
+
+    /// Sends what was held back while replica was paused, if it isn't anymore.
+    fn release(&mut self, id: &Id) {
+        let Some(replica) = self.replicas.get_mut(id) else {
+            return;
+        };
+        if !replica.held || replica.is_paused() {
+            return;
+        }
+        info!("Replica {} resumed", id);
+        replica.held = false;
+        if replica.notify_at.is_none() {
+            self.notify(id);
+        }
+    }
+
+    /// Pauses or resumes replicas with ids, or all replicas without ids, for the control
+    /// interface.
+    fn pause(&mut self, ids: &[&str], paused: bool) -> String {
+        let mut ids: Vec<Id> = ids.iter().map(|id| id.to_string()).collect();
+        if ids.is_empty() {
+            ids = self.replicas.keys().cloned().collect();
+            ids.sort();
+        }
+        if let Some(id) = ids.iter().find(|id| !self.replicas.contains_key(*id)) {
+            return format!("ERROR Unknown replica: {}\n", id);
+        }
+
+        let mut report = String::new();
+        for id in ids {
+            let Some(replica) = self.replicas.get_mut(&id) else {
+                continue;
+            };
+            replica.paused = paused;
+            if paused {
+                report += &format!("replica {} paused\n", id);
+            } else if replica.is_paused() {
+                report += &format!("replica {} still paused by {}\n", id, PAUSE_FILE);
+            } else {
+                self.release(&id);
+                report += &format!("replica {} resumed\n", id);
+            }
+        }
+        report
+    }
+
+    /// Runs the hooks of replica for trigger with changed paths, in the background.
+    fn run_hooks(&mut self, id: &Id, trigger: Trigger, paths: &HashSet<PathBuf>) {
+        let Some(replica) = self.replicas.get(id) else {
+            return;
+        };
+        if paths.is_empty() {
+            return;
+        }
+        for hook in replica
+            .settings
+            .hooks
+            .iter()
+            .filter(|hook| hook.on == trigger)
+        {
+            let concurrency = self.hook_concurrency;
+            self.hooks
+                .get_or_insert_with(|| HookRunner::new(concurrency))
+                .submit(id, &replica.root, hook, paths.iter().cloned());
+        }
+    }
+
+    /// Runs the hooks of replica for settled events with the changes since they last ran.
+    fn run_settled_hooks(&mut self, id: &Id) {
+        let Some(replica) = self.replicas.get_mut(id) else {
+            return;
+        };
+        let changes = std::mem::take(&mut replica.settled_changes);
+        self.run_hooks(id, Trigger::Settled, &changes);
+    }
+
+    /// Time until the monitor next needs an [`Event::Tick`], e.g., for notifying unison of
+    /// changes once events settled. Zero once it is due.
+    pub fn next_tick(&self, now: Instant) -> Duration {
+        let next = self.last_tick + TICK_INTERVAL;
+        let next = self
+            .notify_deadline
+            .map_or(next, |deadline| deadline.min(next));
+        next.saturating_duration_since(now)
+    }
+
+    /// Answers a command of the control interface, see [`crate::control`].
+    pub fn control(&mut self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words.first().copied() {
+            Some("status") => self.status(),
+            Some("pause") => self.pause(&words[1..], true),
+            Some("resume") => self.pause(&words[1..], false),
+            Some(command) => format!("ERROR Unknown command: {}\n", command),
+            None => "ERROR Missing command\n".into(),
+        }
+    }
+
+    /// Report of replicas and watches, for the control interface.
+    fn status(&self) -> String {
+        let mut report = format!("watches: {}\n", self.registry.len());
+        let mut ids: Vec<&Id> = self.replicas.keys().collect();
+        ids.sort();
+        for id in ids {
+            let replica = &self.replicas[id];
+            report += &format!("replica {}: {}\n", id, replica.root.display());
+            let mut paths: Vec<&PathBuf> = replica.paths.iter().collect();
+            paths.sort();
+            for path in paths {
+                report += &format!("    watching {}\n", path.display());
+            }
+            let mut links: Vec<(&PathBuf, &PathBuf)> = replica.links.iter().collect();
+            links.sort();
+            for (link, target) in links {
+                report += &format!("    following {} -> {}\n", link.display(), target.display());
+            }
+            report += &format!(
+                "    pending changes: {}\n    waited on: {}\n    paused: {}\n",
+                replica.pending_changes.len(),
+                if replica.waited_on { "yes" } else { "no" },
+                if replica.is_paused() { "yes" } else { "no" }
+            );
+        }
+        report
+    }
+
+    /// Serves requests read line by line from reader along with filesystem events, until reader
+    /// reaches end of input or a [`ProtocolError`] occurs. Other errors are logged and skipped.
+    pub fn run<READ>(&mut self, reader: READ, fsevents: Receiver<FsEvent>) -> Fallible<()>
+    where
+        READ: BufRead + Send + 'static,
+    {
+        self.run_with_control(reader, fsevents, channel().1)
+    }
+
+    /// Same as [`Monitor::run`], answering commands of the control interface from controls as
+    /// well.
+    pub fn run_with_control<READ>(
+        &mut self,
+        reader: READ,
+        fsevents: Receiver<FsEvent>,
+        controls: Receiver<ControlRequest>,
+    ) -> Fallible<()>
+    where
+        READ: BufRead + Send + 'static,
+    {
+        let (tx, rx) = channel();
+
+        let tx_clone = tx.clone();
+        thread::spawn(move || -> Fallible<()> {
+            for request in controls {
+                tx_clone.send(Some(Event::Control(request)))?;
+            }
+            Ok(())
+        });
+
+        let tx_clone = tx.clone();
+        thread::spawn(move || -> Fallible<()> {
+            for input in reader.lines() {
+                tx_clone.send(Some(Event::Input(input?)))?;
+            }
+            tx_clone.send(None)?;
+            Ok(())
+        });
+
+        thread::spawn(move || -> Fallible<()> {
+            for event in fsevents {
+                tx.send(Some(Event::FSEvent(event)))?;
+            }
+            Ok(())
+        });
+
+        loop {
+            let event = match rx.recv_timeout(self.next_tick(Instant::now())) {
+                Ok(Some(event)) => event,
+                Ok(None) | Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => Event::Tick(Instant::now()),
+            };
+            // Ticks go through all replicas, so only when due, also amid a stream of events.
+            let result = self.handle_event(event).and_then(|_| {
+                let now = Instant::now();
+                if self.next_tick(now).is_zero() {
+                    self.handle_event(Event::Tick(now))
+                } else {
+                    Ok(())
+                }
+            });
+            if let Err(e) = result {
+                if e.downcast_ref::<ProtocolError>().is_some() {
+                    return Err(e);
+                }
+                error!("Error handling event: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, response: Response) {
+        self.last_output = Instant::now();
+        let output = response.to_string();
+
+        debug!(">> {}", output);
+        let _ = writeln!(self.writer, "{}", output);
+        let _ = self.writer.flush();
+    }
+
+    fn send_error(&mut self, msg: &str) -> ProtocolError {
+        self.send(Response::Error(msg.to_owned()));
+        ProtocolError(msg.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use failure::bail;
+    use std::cell::RefCell;
+    use std::io::{BufRead, Cursor};
+    use std::rc::Rc;
+
+    struct Watcher {}
+
+    impl Watch for Watcher {}
+
+    /// Records backend calls.
+    #[derive(Default)]
+    struct RecordingWatcher {
+        calls: Vec<String>,
+    }
+
+    impl Watch for RecordingWatcher {
+        fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Fallible<()> {
+            self.calls
+                .push(format!("watch {} {:?}", path.display(), recursive_mode));
+            Ok(())
+        }
+
+        fn unwatch(&mut self, path: &Path) -> Fallible<()> {
+            self.calls.push(format!("unwatch {}", path.display()));
+            Ok(())
+        }
+    }
+
+    /// Watcher which has run out of watches.
+    struct ExhaustedWatcher {
+        can_poll: bool,
+    }
+
+    impl Watch for ExhaustedWatcher {
+        fn watch(&mut self, _path: &Path, _recursive_mode: RecursiveMode) -> Fallible<()> {
+            Err(notify::Error::Io(std::io::Error::from_raw_os_error(28)).into())
+        }
+
+        fn poll(&mut self, path: &Path, _recursive_mode: RecursiveMode) -> Fallible<()> {
+            if !self.can_poll {
+                bail!("Polling {:?} not supported", path);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_version() {
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("VERSION 1\n".into()))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<Result<Vec<String>, _>>()
+                .unwrap(),
+            vec!["VERSION 1"]
+        );
+    }
+
+    #[test]
+    fn test_version_negotiation() {
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("VERSION 1 99 extension\n".into()))
+            .unwrap();
+        assert!(
+            monitor
+                .handle_event(Event::Input("VERSION 99\n".into()))
+                .unwrap_err()
+                .downcast_ref::<ProtocolError>()
+                .is_some()
+        );
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<Result<Vec<String>, _>>()
+                .unwrap(),
+            vec!["VERSION 1", "ERROR Unsupported%20versions%3A%20%5B99%5D"]
+        );
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+
+        // Without negotiation, neither keepalive nor idle timeout.
+        let start = Instant::now();
+        monitor
+            .handle_event(Event::Tick(start + Duration::from_secs(3600)))
+            .unwrap();
+
+        monitor
+            .handle_event(Event::Input("VERSION 1 keepalive\n".into()))
+            .unwrap();
+        let start = monitor.last_output;
+        monitor
+            .handle_event(Event::Tick(start + monitor.keepalive_interval / 2))
+            .unwrap();
+        monitor
+            .handle_event(Event::Tick(start + monitor.keepalive_interval))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("KEEPALIVE\n".into()))
+            .unwrap();
+        let start = monitor.last_input;
+        monitor
+            .handle_event(Event::Tick(start + monitor.idle_timeout / 2))
+            .unwrap();
+        assert!(
+            monitor
+                .handle_event(Event::Tick(start + monitor.idle_timeout))
+                .unwrap_err()
+                .downcast_ref::<ProtocolError>()
+                .is_some()
+        );
+
+        monitor.writer.set_position(0);
+        let lines = monitor
+            .writer
+            .lines()
+            .collect::<Result<Vec<String>, _>>()
+            .unwrap();
+        assert_eq!(&lines[..2], &["VERSION 1 keepalive", "KEEPALIVE"]);
+        assert!(lines[2].starts_with("ERROR "));
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn test_start() {
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        let id = "123";
+        let root = PathBuf::from("/tmp/sample");
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START {} {}\n",
+                id,
+                root.to_string_lossy()
+            )))
+            .unwrap();
+
+        assert_eq!(monitor.replicas.len(), 1);
+        assert!(monitor.replicas.contains_key(id));
+        assert_eq!(monitor.replicas.get(id).unwrap().root, root);
+        assert!(monitor.replicas.get(id).unwrap().paths.contains(&root));
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<Result<Vec<String>, _>>()
+                .unwrap(),
+            vec!["OK"]
+        );
+    }
+
+    #[test]
+    fn test_start_with_subdir() {
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        let id = "123";
+        let root = PathBuf::from("/tmp/sample");
+        let subdir = PathBuf::from("subdir");
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START {} {} {}\n",
+                id,
+                root.to_string_lossy(),
+                subdir.to_string_lossy()
+            )))
+            .unwrap();
+
+        assert_eq!(monitor.replicas.len(), 1);
+        assert!(monitor.replicas.contains_key(id));
+        assert_eq!(monitor.replicas.get(id).unwrap().root, root);
+        assert!(
+            monitor
+                .replicas
+                .get(id)
+                .unwrap()
+                .paths
+                .contains(&root.join(&subdir))
+        );
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<Result<Vec<String>, _>>()
+                .unwrap(),
+            vec!["OK"]
+        );
+    }
+
+    #[test]
+    fn test_dir() {
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+
+        monitor.handle_event(Event::Input("DIR\n".into())).unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<Result<Vec<String>, _>>()
+                .unwrap(),
+            vec!["OK"]
+        );
+    }
+
+    #[test]
+    fn test_dir_with_dir() {
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("DIR dir\n".into()))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<Result<Vec<String>, _>>()
+                .unwrap(),
+            vec!["OK"]
+        );
+    }
+
+    #[test]
+    fn test_follow_link() {
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        let id = "123";
+        let root = PathBuf::from("/usr/bin");
+        let file = PathBuf::from("env");
+
+        monitor
+            .handle_event(Event::Input(format!(
+                "START {} {} {}\n",
+                id,
+                root.to_string_lossy(),
+                file.to_string_lossy()
+            )))
+            .unwrap();
+        monitor.handle_event(Event::Input("LINK\n".into())).unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<Result<Vec<String>, _>>()
+                .unwrap(),
+            vec!["OK", "OK"]
+        );
+    }
+
+    #[test]
+    fn test_reset_overlapping_replicas() {
+        let mut monitor = Monitor::new(RecordingWatcher::default(), Cursor::new(vec![]));
+
+        monitor
+            .handle_event(Event::Input("START 1 %2Ftmp%2Fsample subdir\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("START 2 %2Ftmp%2Fsample\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("RESET 2\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("RESET 1\n".into()))
+            .unwrap();
+
+        assert_eq!(
+            monitor.watcher.calls,
+            [
+                "watch /tmp/sample/subdir Recursive",
+                "watch /tmp/sample Recursive",
+                "unwatch /tmp/sample/subdir",
+                "watch /tmp/sample/subdir Recursive",
+                "unwatch /tmp/sample",
+                "unwatch /tmp/sample/subdir",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reset_unwatches_link() {
+        let mut monitor = Monitor::new(RecordingWatcher::default(), Cursor::new(vec![]));
+        let realpath = PathBuf::from("/usr/bin/env").canonicalize().unwrap();
+
+        monitor
+            .handle_event(Event::Input("START 1 %2Fusr%2Fbin env\n".into()))
+            .unwrap();
+        monitor.handle_event(Event::Input("LINK\n".into())).unwrap();
+        assert_eq!(monitor.link_map.iter().len(), 1);
+        monitor
+            .handle_event(Event::Input("RESET 1\n".into()))
+            .unwrap();
+
+        assert!(monitor.replicas.is_empty());
+        assert!(monitor.registry.is_empty());
+        assert!(monitor.link_map.is_empty());
+        assert!(monitor.link_locations.is_empty());
+        assert!(
+            monitor
+                .watcher
+                .calls
+                .contains(&format!("unwatch {}", realpath.display()))
+        );
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        let id = "123";
+        let root = "/tmp/sample";
+        let filename = "filename";
+
+        monitor
+            .handle_event(Event::Input(format!("START {} {}\n", id, root)))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(
+                EventKind::Create,
+                &PathBuf::from(root).join(filename),
+            )))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("WAIT {}\n", id)))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("CHANGES {}\n", id)))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<Result<Vec<String>, _>>()
+                .unwrap(),
+            vec![
+                "OK",
+                &format!("CHANGES {}", id),
+                &format!("RECURSIVE {}", filename),
+                "DONE"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_changes_after_wait() {
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        let id = "123";
+        let root = "/tmp/sample";
+        let filename = "filename";
+
+        monitor
+            .handle_event(Event::Input(format!("START {} {}\n", id, root)))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("WAIT {}\n", id)))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(
+                EventKind::Create,
+                &PathBuf::from(root).join(filename),
+            )))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("CHANGES {}\n", id)))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<Result<Vec<String>, _>>()
+                .unwrap(),
+            vec![
+                "OK",
+                &format!("CHANGES {}", id),
+                &format!("RECURSIVE {}", filename),
+                "DONE"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_changes_with_subdir() {
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        let id = "123";
+        let root = "/tmp/sample";
+        let subdir = "subdir";
+        let filename = "filename";
+
+        monitor
+            .handle_event(Event::Input(format!("START {} {} {}\n", id, root, subdir)))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(
+                EventKind::Create,
+                &PathBuf::from(root).join(subdir).join(filename),
+            )))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("WAIT {}\n", id)))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("CHANGES {}\n", id)))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<Result<Vec<String>, _>>()
+                .unwrap(),
+            vec![
+                "OK",
+                &format!("CHANGES {}", id),
+                &format!("RECURSIVE {}%2F{}", subdir, filename),
+                "DONE"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_changes_no_wait() {
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        let id = "123";
+        let root = "/tmp/sample";
+        let filename = "filename";
+
+        monitor
+            .handle_event(Event::Input(format!("START {} {}\n", id, root)))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(
+                EventKind::Create,
+                &PathBuf::from(root).join(filename),
+            )))
+            .unwrap();
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<Result<Vec<String>, _>>()
+                .unwrap(),
+            vec!["OK",]
+        );
+    }
+
+    #[test]
+    fn test_watch_limit() {
+        let mut monitor = Monitor::new(ExhaustedWatcher { can_poll: false }, Cursor::new(vec![]));
+        let id = "123";
+
+        monitor
+            .handle_event(Event::Input(format!("START {} /tmp/sample subdir\n", id)))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input(format!("WAIT {}\n", id)))
+            .unwrap();
+        for _ in 0..2 {
+            monitor
+                .handle_event(Event::Input(format!("CHANGES {}\n", id)))
+                .unwrap();
+        }
+
+        monitor.writer.set_position(0);
+        assert_eq!(
+            monitor
+                .writer
+                .lines()
+                .collect::<Result<Vec<String>, _>>()
+                .unwrap(),
+            vec![
+                "OK",
+                &format!("CHANGES {}", id),
+                "RECURSIVE subdir",
+                "DONE",
+                "RECURSIVE subdir",
+                "DONE"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_watch_limit_polling() {
+        let mut monitor = Monitor::new(ExhaustedWatcher { can_poll: true }, Cursor::new(vec![]));
+        let id = "123";
+
+        monitor
+            .handle_event(Event::Input(format!("START {} /tmp/sample\n", id)))
+            .unwrap();
+
+        assert!(monitor.registry.is_watching(Path::new("/tmp/sample")));
+        assert!(monitor.replicas[id].always_changed.is_empty());
+    }
+
+    #[test]
+    fn test_rescan_all() {
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+
+        for (id, root) in [("1", "/tmp/a"), ("2", "/tmp/b")] {
+            monitor
+                .handle_event(Event::Input(format!("START {} {}\n", id, root)))
+                .unwrap();
+        }
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::rescan_all()))
+            .unwrap();
+
+        for id in ["1", "2"] {
+            assert_eq!(
+                monitor.replicas[id].pending_changes,
+                HashSet::from([PathBuf::new()])
+            );
+        }
+    }
+
+    /// Shared log of watched created directories and replies to unison, in order.
+    #[derive(Clone, Default)]
+    struct Log(Rc<RefCell<Vec<String>>>);
+
+    impl Write for Log {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut log = self.0.borrow_mut();
+            let output = String::from_utf8_lossy(buf);
+            // Replies are written separately from their newlines.
+            log.extend(
+                output
+                    .lines()
+                    .filter(|line| !line.is_empty())
+                    .map(String::from),
+            );
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Writes a file into new directories right before they get watched, like `tar x` racing
+    /// the backend.
+    struct RacingWatcher {
+        log: Log,
+    }
+
+    impl Watch for RacingWatcher {
+        fn watch_created(&mut self, path: &Path) -> Fallible<()> {
+            std::fs::write(path.join("before-watch"), "")?;
+            self.log
+                .0
+                .borrow_mut()
+                .push(format!("watch_created {}", path.display()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_created_dir_scan() {
+        let root =
+            std::env::temp_dir().join(format!("unison-fsmonitor-created-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("new/nested")).unwrap();
+        std::fs::write(root.join("new/nested/filename"), "").unwrap();
+
+        let log = Log::default();
+        let watcher = RacingWatcher { log: log.clone() };
+        let mut monitor = Monitor::new(watcher, log.clone());
+        monitor
+            .handle_event(Event::Input(format!("START 123 {}\n", root.display())))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("WAIT 123\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(
+                EventKind::Create,
+                &root.join("new"),
+            )))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("CHANGES 123\n".into()))
+            .unwrap();
+
+        // Watched before unison is told to scan it, so that nothing created after the scan is
+        // missed. Entries created before have no events, but unison finds them in its scan.
+        assert_eq!(
+            log.0.borrow()[1..],
+            [
+                format!("watch_created {}", root.join("new").display()),
+                "CHANGES 123".into(),
+                "RECURSIVE new".into(),
+                "DONE".into(),
+            ]
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_recreated_root() {
+        let parent =
+            std::env::temp_dir().join(format!("unison-fsmonitor-recreated-{}", std::process::id()));
+        let root = parent.join("root");
+        let _ = std::fs::remove_dir_all(&parent);
+        std::fs::create_dir_all(&root).unwrap();
+
+        let mut monitor = Monitor::new(RecordingWatcher::default(), Cursor::new(vec![]));
+        monitor
+            .handle_event(Event::Input(format!("START 123 {}\n", root.display())))
+            .unwrap();
+        monitor.watcher.calls.clear();
+
+        std::fs::remove_dir_all(&root).unwrap();
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(EventKind::Remove, &root)))
+            .unwrap();
+        assert_eq!(
+            std::mem::take(&mut monitor.watcher.calls),
+            [
+                format!("unwatch {}", root.display()),
+                format!("watch {} NonRecursive", parent.display())
+            ]
+        );
+        // Reported as a change of the whole replica.
+        assert!(
+            monitor.replicas["123"]
+                .pending_changes
+                .contains(Path::new(""))
+        );
+
+        // Parent goes away as well, its own parent is watched instead.
+        std::fs::remove_dir_all(&parent).unwrap();
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(EventKind::Remove, &parent)))
+            .unwrap();
+        assert_eq!(
+            std::mem::take(&mut monitor.watcher.calls),
+            [
+                format!("unwatch {}", parent.display()),
+                format!("watch {} NonRecursive", std::env::temp_dir().display())
+            ]
+        );
+
+        std::fs::create_dir(&parent).unwrap();
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(EventKind::Create, &parent)))
+            .unwrap();
+        assert_eq!(monitor.lost[&root], parent);
+        assert_eq!(
+            std::mem::take(&mut monitor.watcher.calls),
+            [
+                format!("unwatch {}", std::env::temp_dir().display()),
+                format!("watch {} NonRecursive", parent.display())
+            ]
+        );
+
+        std::fs::create_dir(&root).unwrap();
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(EventKind::Create, &root)))
+            .unwrap();
+        assert_eq!(
+            std::mem::take(&mut monitor.watcher.calls),
+            [
+                format!("unwatch {}", parent.display()),
+                format!("watch {} Recursive", root.display())
+            ]
+        );
+        assert!(monitor.lost.is_empty());
+
+        std::fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[test]
+    fn test_mounts_changed() {
+        let mount = |id, mount_point: &str| Mount {
+            id,
+            mount_point: mount_point.into(),
+            fs_type: "ext4".into(),
+            source: "/dev/sda1".into(),
+        };
+        let mut monitor = Monitor::new(RecordingWatcher::default(), Cursor::new(vec![]));
+        monitor
+            .handle_event(Event::Input("START 123 /tmp/sample\n".into()))
+            .unwrap();
+        monitor.mounts = Some(vec![mount(1, "/")]);
+        monitor.watcher.calls.clear();
+
+        // Unrelated.
+        monitor
+            .handle_mounts(vec![mount(1, "/"), mount(2, "/mnt/other")])
+            .unwrap();
+        assert!(monitor.watcher.calls.is_empty());
+
+        // Mounted inside the replica.
+        monitor
+            .handle_mounts(vec![mount(1, "/"), mount(3, "/tmp/sample/usb")])
+            .unwrap();
+        assert_eq!(
+            std::mem::take(&mut monitor.watcher.calls),
+            ["unwatch /tmp/sample", "watch /tmp/sample Recursive"]
+        );
+        assert_eq!(
+            std::mem::take(&mut monitor.replicas.get_mut("123").unwrap().pending_changes),
+            HashSet::from([PathBuf::from("usb")])
+        );
+
+        // Mounted over the replica.
+        monitor
+            .handle_mounts(vec![
+                mount(1, "/"),
+                mount(3, "/tmp/sample/usb"),
+                mount(4, "/tmp"),
+            ])
+            .unwrap();
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            HashSet::from([PathBuf::new()])
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_link_loops() {
+        use std::os::unix::fs::symlink;
+
+        let base =
+            std::env::temp_dir().join(format!("unison-fsmonitor-loops-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let (root, ext) = (base.join("root"), base.join("ext"));
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(&ext).unwrap();
+        symlink("..", root.join("a/loop")).unwrap();
+        symlink("sub", root.join("alias")).unwrap();
+        symlink(&ext, root.join("ext")).unwrap();
+        symlink(&root, ext.join("back")).unwrap();
+        let root = root.canonicalize().unwrap();
+        let ext = ext.canonicalize().unwrap();
+
+        let mut monitor = Monitor::new(RecordingWatcher::default(), Cursor::new(vec![]));
+        monitor
+            .handle_event(Event::Input(format!("START 123 {}\n", root.display())))
+            .unwrap();
+        for link in ["a/loop", "alias", "ext", "ext/back"] {
+            monitor
+                .handle_event(Event::Input(Request::Link(Some(link.into())).to_string()))
+                .unwrap();
+        }
+
+        // Loops aren't watched.
+        assert_eq!(
+            monitor.watcher.calls,
+            [
+                format!("watch {} Recursive", root.display()),
+                format!("watch {} Recursive", ext.display())
+            ]
+        );
+        assert_eq!(monitor.link_map.iter().len(), 2);
+
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(
+                EventKind::ModifyData,
+                &root.join("sub/filename"),
+            )))
+            .unwrap();
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            HashSet::from(["sub/filename", "alias/filename"].map(PathBuf::from))
+        );
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_repointed_link() {
+        use std::os::unix::fs::symlink;
+
+        let base =
+            std::env::temp_dir().join(format!("unison-fsmonitor-relink-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let root = base.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(base.join("releases/v1")).unwrap();
+        std::fs::create_dir_all(base.join("releases/v2")).unwrap();
+        symlink("../releases/v1", root.join("current")).unwrap();
+        let root = root.canonicalize().unwrap();
+        let releases = base.join("releases").canonicalize().unwrap();
+
+        let mut monitor = Monitor::new(RecordingWatcher::default(), Cursor::new(vec![]));
+        monitor
+            .handle_event(Event::Input(format!("START 123 {}\n", root.display())))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("LINK current\n".into()))
+            .unwrap();
+
+        // Deploy-style swap.
+        symlink("../releases/v2", root.join("next")).unwrap();
+        std::fs::rename(root.join("next"), root.join("current")).unwrap();
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(
+                EventKind::RenameTo,
+                &root.join("current"),
+            )))
+            .unwrap();
+
+        assert_eq!(
+            monitor.watcher.calls,
+            [
+                format!("watch {} Recursive", root.display()),
+                format!("watch {} Recursive", releases.join("v1").display()),
+                format!("unwatch {}", releases.join("v1").display()),
+                format!("watch {} Recursive", releases.join("v2").display()),
+            ]
+        );
+        let replica = monitor.replicas.get_mut("123").unwrap();
+        assert_eq!(
+            replica.pending_changes.drain().collect::<HashSet<_>>(),
+            HashSet::from([PathBuf::from("current")])
+        );
+
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(
+                EventKind::ModifyData,
+                &releases.join("v2/filename"),
+            )))
+            .unwrap();
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            HashSet::from([PathBuf::from("current/filename")])
+        );
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_links_per_replica() {
+        let base =
+            std::env::temp_dir().join(format!("unison-fsmonitor-owners-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(base.join("sub")).unwrap();
+        std::os::unix::fs::symlink("sub", base.join("alias")).unwrap();
+        let root = base.canonicalize().unwrap();
+        let event = FsEvent::new(EventKind::ModifyData, &root.join("sub/filename"));
+
+        let mut monitor = Monitor::new(RecordingWatcher::default(), Cursor::new(vec![]));
+        for id in ["1", "2"] {
+            monitor
+                .handle_event(Event::Input(format!("START {} {}\n", id, root.display())))
+                .unwrap();
+        }
+        monitor
+            .handle_event(Event::Input(format!("START 1 {} alias\n", root.display())))
+            .unwrap();
+        monitor.handle_event(Event::Input("LINK\n".into())).unwrap();
+
+        monitor.handle_event(Event::FSEvent(event.clone())).unwrap();
+        assert_eq!(
+            monitor.replicas["1"].pending_changes,
+            HashSet::from(["sub/filename", "alias/filename"].map(PathBuf::from))
+        );
+        assert_eq!(
+            monitor.replicas["2"].pending_changes,
+            HashSet::from([PathBuf::from("sub/filename")])
+        );
+
+        monitor
+            .handle_event(Event::Input("RESET 1\n".into()))
+            .unwrap();
+        assert_eq!(
+            monitor.registry.requested_paths().collect::<Vec<_>>(),
+            [root.as_path()]
+        );
+        monitor
+            .replicas
+            .get_mut("2")
+            .unwrap()
+            .pending_changes
+            .clear();
+        monitor.handle_event(Event::FSEvent(event)).unwrap();
+        assert_eq!(
+            monitor.replicas["2"].pending_changes,
+            HashSet::from([PathBuf::from("sub/filename")])
+        );
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_normalization() {
+        let nfc = "caf\u{e9}";
+        let nfd = "cafe\u{301}";
+        let root = PathBuf::from("/tmp/sample");
+        // Same names in both forms, e.g., one side synced from macOS.
+        let events = [
+            root.join(nfd).join("filename"),
+            root.join(nfc).join("filename"),
+            root.join(nfc).join(nfd),
+        ];
+
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        monitor.normalization = Normalization::Nfc;
+        monitor
+            .handle_event(Event::Input(
+                Request::Start {
+                    replica: "123".into(),
+                    root: root.clone(),
+                    subdir: Some(nfd.into()),
+                }
+                .to_string(),
+            ))
+            .unwrap();
+        // Not on disk, so watched as named by unison.
+        assert_eq!(monitor.current_path, root.join(nfd));
+
+        for path in &events {
+            monitor
+                .handle_event(Event::FSEvent(FsEvent::new(EventKind::ModifyData, path)))
+                .unwrap();
+        }
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            HashSet::from([Path::new(nfc).join("filename"), Path::new(nfc).join(nfc)])
+        );
+
+        // Left alone by default.
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        monitor
+            .handle_event(Event::Input(format!("START 123 {}\n", root.display())))
+            .unwrap();
+        for path in &events {
+            monitor
+                .handle_event(Event::FSEvent(FsEvent::new(EventKind::ModifyData, path)))
+                .unwrap();
+        }
+        assert_eq!(monitor.replicas["123"].pending_changes.len(), 3);
+    }
+
+    #[test]
+    fn test_normalized_subdir() {
+        let nfc = "caf\u{e9}";
+        let nfd = "cafe\u{301}";
+        let root =
+            std::env::temp_dir().join(format!("unison-fsmonitor-nfd-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join(nfd)).unwrap();
+
+        let mut monitor = Monitor::new(RecordingWatcher::default(), Cursor::new(vec![]));
+        monitor.normalization = Normalization::Nfc;
+        monitor
+            .handle_event(Event::Input(
+                Request::Start {
+                    replica: "123".into(),
+                    root: root.clone(),
+                    subdir: Some(nfc.into()),
+                }
+                .to_string(),
+            ))
+            .unwrap();
+        assert_eq!(
+            monitor.watcher.calls,
+            [format!("watch {} Recursive", root.join(nfd).display())]
+        );
+
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(
+                EventKind::ModifyData,
+                &root.join(nfd).join("filename"),
+            )))
+            .unwrap();
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            HashSet::from([Path::new(nfc).join("filename")])
+        );
+
+        // Watched once, whichever form unison uses.
+        monitor
+            .handle_event(Event::Input(
+                Request::Start {
+                    replica: "123".into(),
+                    root: root.clone(),
+                    subdir: Some(nfd.into()),
+                }
+                .to_string(),
+            ))
+            .unwrap();
+        assert_eq!(monitor.watcher.calls.len(), 1);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_ignore_case() {
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        monitor.ignore_case = true;
+        monitor
+            .handle_event(Event::Input("START 123 %2Ftmp%2FSample Sub\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(
+                EventKind::ModifyData,
+                Path::new("/tmp/sample/sub/File"),
+            )))
+            .unwrap();
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            HashSet::from([PathBuf::from("sub/File")])
+        );
+        assert!(monitor.is_watching(Path::new("/TMP/SAMPLE/SUB")));
+
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        monitor
+            .handle_event(Event::Input("START 123 %2Ftmp%2FSample Sub\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(
+                EventKind::ModifyData,
+                Path::new("/tmp/sample/sub/File"),
+            )))
+            .unwrap();
+        assert!(monitor.replicas["123"].pending_changes.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ignore_case_links() {
+        let base =
+            std::env::temp_dir().join(format!("unison-fsmonitor-case-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(base.join("sub")).unwrap();
+        std::os::unix::fs::symlink("sub", base.join("alias")).unwrap();
+        let root = base.canonicalize().unwrap();
+
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        monitor.ignore_case = true;
+        monitor
+            .handle_event(Event::Input(format!("START 123 {}\n", root.display())))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("LINK alias\n".into()))
+            .unwrap();
+        // E.g., reported in another case on a case-insensitive directory.
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(
+                EventKind::ModifyData,
+                &root.join("SUB/filename"),
+            )))
+            .unwrap();
+        assert_eq!(
+            monitor.replicas["123"].pending_changes,
+            HashSet::from(["SUB/filename", "alias/filename"].map(PathBuf::from))
+        );
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_replica_config() {
+        let config = crate::config::Config::parse(
+            r#"
+ignore = ["*.swp"]
+
+[roots."/tmp/sample"]
+debounce = 1
+"#,
+        )
+        .unwrap();
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        monitor.replica_config = config.replicas;
+
+        monitor
+            .handle_event(Event::Input("START 1 %2Ftmp%2Fsample\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::Input("START 2 %2Ftmp%2Fother\n".into()))
+            .unwrap();
+        for id in ["1", "2"] {
+            monitor
+                .handle_event(Event::Input(format!("WAIT {}\n", id)))
+                .unwrap();
+        }
+        for path in [
+            "/tmp/sample/.filename.swp",
+            "/tmp/sample/filename",
+            "/tmp/other/filename",
+        ] {
+            monitor
+                .handle_event(Event::FSEvent(FsEvent::new(
+                    EventKind::ModifyData,
+                    Path::new(path),
+                )))
+                .unwrap();
+        }
+        assert_eq!(
+            monitor.replicas["1"].pending_changes,
+            HashSet::from([PathBuf::from("filename")])
+        );
+
+        // Only replica 2 is notified right away.
+        let lines = |monitor: &mut Monitor<Watcher, Cursor<Vec<u8>>>| {
+            monitor.writer.set_position(0);
+            let lines: Vec<String> = (&mut monitor.writer).lines().map(Result::unwrap).collect();
+            monitor.writer = Cursor::new(vec![]);
+            lines
+        };
+        assert_eq!(lines(&mut monitor), ["OK", "OK", "CHANGES 2"]);
+        let now = Instant::now();
+        assert!(monitor.next_tick(now) <= Duration::from_secs(1));
+        assert!(monitor.next_tick(now + Duration::from_secs(1)).is_zero());
+        monitor.handle_event(Event::Tick(now)).unwrap();
+        assert!(lines(&mut monitor).is_empty());
+        monitor
+            .handle_event(Event::Tick(now + Duration::from_secs(2)))
+            .unwrap();
+        assert_eq!(lines(&mut monitor), ["CHANGES 1"]);
+    }
+
+    #[test]
+    fn test_control() {
+        let mut monitor = Monitor::new(RecordingWatcher::default(), Cursor::new(vec![]));
+        monitor
+            .handle_event(Event::Input("START 1 /tmp/sample\n".into()))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(
+                EventKind::Create,
+                Path::new("/tmp/sample/filename"),
+            )))
+            .unwrap();
+
+        let (tx, rx) = channel();
+        monitor
+            .handle_event(Event::Control(ControlRequest {
+                command: "status".into(),
+                reply: tx,
+            }))
+            .unwrap();
+        assert_eq!(
+            rx.recv().unwrap(),
+            "watches: 1\n\
+             replica 1: /tmp/sample\n\
+             \x20   watching /tmp/sample\n\
+             \x20   pending changes: 1\n\
+             \x20   waited on: no\n\
+             \x20   paused: no\n"
+        );
+        assert_eq!(monitor.control("other"), "ERROR Unknown command: other\n");
+        assert_eq!(monitor.control(""), "ERROR Missing command\n");
+    }
+
+    #[test]
+    fn test_hooks() {
+        let root =
+            std::env::temp_dir().join(format!("unison-fsmonitor-hooks-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let config = crate::config::Config::parse(
+            r#"
+debounce = 1
+
+[[hooks]]
+command = "cat >> settled"
+on = "settled"
+
+[[hooks]]
+command = "cat >> changes"
+"#,
+        )
+        .unwrap();
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        monitor.replica_config = config.replicas;
+        monitor
+            .handle_event(Event::Input(format!("START 1 {}\n", root.display())))
+            .unwrap();
+        monitor
+            .handle_event(Event::FSEvent(FsEvent::new(
+                EventKind::Create,
+                &root.join("filename"),
+            )))
+            .unwrap();
+
+        let read = |name: &str| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                let content = std::fs::read_to_string(root.join(name)).unwrap_or_default();
+                if !content.is_empty() || Instant::now() > deadline {
+                    return content;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        };
+        // Events settle whether unison waits or not.
+        monitor
+            .handle_event(Event::Tick(Instant::now() + Duration::from_secs(2)))
+            .unwrap();
+        assert_eq!(read("settled"), "filename\n");
+        assert!(monitor.replicas["1"].settled_changes.is_empty());
+
+        monitor
+            .handle_event(Event::Input("CHANGES 1\n".into()))
+            .unwrap();
+        assert_eq!(read("changes"), "filename\n");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_pause() {
+        let root =
+            std::env::temp_dir().join(format!("unison-fsmonitor-pause-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let mut monitor = Monitor::new(Watcher {}, Cursor::new(vec![]));
+        let lines = |monitor: &mut Monitor<Watcher, Cursor<Vec<u8>>>| {
+            monitor.writer.set_position(0);
+            let lines: Vec<String> = (&mut monitor.writer).lines().map(Result::unwrap).collect();
+            monitor.writer = Cursor::new(vec![]);
+            lines
+        };
+        let input = |monitor: &mut Monitor<Watcher, Cursor<Vec<u8>>>, input: String| {
+            monitor.handle_event(Event::Input(input)).unwrap();
+        };
+        let change = |monitor: &mut Monitor<Watcher, Cursor<Vec<u8>>>, name: &str| {
+            monitor
+                .handle_event(Event::FSEvent(FsEvent::new(
+                    EventKind::Create,
+                    &root.join(name),
+                )))
+                .unwrap();
+        };
+
+        input(&mut monitor, format!("START 1 {}\n", root.display()));
+        input(&mut monitor, "WAIT 1\n".into());
+        assert_eq!(monitor.control("pause 1"), "replica 1 paused\n");
+        change(&mut monitor, "filename");
+        assert_eq!(lines(&mut monitor), ["OK"]);
+        assert_eq!(monitor.control("resume"), "replica 1 resumed\n");
+        assert_eq!(lines(&mut monitor), ["CHANGES 1"]);
+        input(&mut monitor, "CHANGES 1\n".into());
+        assert_eq!(lines(&mut monitor), ["RECURSIVE filename", "DONE"]);
+
+        // Paused while the pause file exists, whose changes aren't reported.
+        std::fs::write(root.join(PAUSE_FILE), "").unwrap();
+        input(&mut monitor, "WAIT 1\n".into());
+        change(&mut monitor, PAUSE_FILE);
+        change(&mut monitor, "other");
+        assert!(lines(&mut monitor).is_empty());
+        assert_eq!(
+            monitor.control("resume 1"),
+            format!("replica 1 still paused by {}\n", PAUSE_FILE)
+        );
+        // Noticed with the next tick, without an event.
+        std::fs::remove_file(root.join(PAUSE_FILE)).unwrap();
+        assert!(monitor.replicas["1"].is_paused());
+        monitor.handle_event(Event::Tick(Instant::now())).unwrap();
+        assert_eq!(lines(&mut monitor), ["CHANGES 1"]);
+        assert_eq!(
+            monitor.replicas["1"].pending_changes,
+            HashSet::from([PathBuf::from("other")])
+        );
+
+        assert_eq!(monitor.control("pause 2"), "ERROR Unknown replica: 2\n");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
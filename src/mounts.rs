@@ -0,0 +1,188 @@
+//! Mounted filesystems, from `/proc/self/mountinfo`.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// Path of the mount table of the current process.
+pub const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    /// Unique id of the mount, which changes when a filesystem is mounted again.
+    pub id: u32,
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub source: String,
+}
+
+impl Mount {
+    /// Whether changes made on other hosts never produce events, e.g., NFS or sshfs.
+    pub fn is_remote(&self) -> bool {
+        const REMOTE: &[&str] = &[
+            "nfs",
+            "nfs4",
+            "cifs",
+            "smb3",
+            "smbfs",
+            "afs",
+            "9p",
+            "ceph",
+            "glusterfs",
+            "lustre",
+            "gpfs",
+            "fuse",
+        ];
+        // fuseblk is used by local block device filesystems, e.g., ntfs-3g.
+        REMOTE.contains(&self.fs_type.as_str()) || self.fs_type.starts_with("fuse.")
+    }
+}
+
+/// Reads the mount table of the current process. Empty where unavailable.
+pub fn read_mounts() -> Vec<Mount> {
+    std::fs::read_to_string(MOUNTINFO)
+        .map(|content| parse_mountinfo(&content))
+        .unwrap_or_default()
+}
+
+/// Parses mountinfo, see proc(5). Malformed lines are skipped.
+pub fn parse_mountinfo(content: &str) -> Vec<Mount> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let separator = fields.iter().position(|field| *field == "-")?;
+            Some(Mount {
+                id: fields.first()?.parse().ok()?,
+                mount_point: unescape(fields.get(4)?).into(),
+                fs_type: unescape(fields.get(separator + 1)?),
+                source: unescape(fields.get(separator + 2)?),
+            })
+        })
+        .collect()
+}
+
+/// Undoes octal escapes like `\040` for space.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut output = vec![];
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'\\'
+            && idx + 3 < bytes.len()
+            && bytes[idx + 1..idx + 4].iter().all(u8::is_ascii_digit)
+            && let Ok(byte) = u8::from_str_radix(&field[idx + 1..idx + 4], 8)
+        {
+            output.push(byte);
+            idx += 4;
+        } else {
+            output.push(bytes[idx]);
+            idx += 1;
+        }
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+/// Mount points of mounts added or removed between the two tables.
+pub fn changed_mount_points(previous: &[Mount], current: &[Mount]) -> BTreeSet<PathBuf> {
+    let added = current.iter().filter(|mount| !previous.contains(mount));
+    let removed = previous.iter().filter(|mount| !current.contains(mount));
+    added
+        .chain(removed)
+        .map(|mount| mount.mount_point.clone())
+        .collect()
+}
+
+/// Mount path is on, i.e., the one with the longest mount point containing path.
+pub fn mount_of<'a>(mounts: &'a [Mount], path: &Path) -> Option<&'a Mount> {
+    // Later mounts shadow earlier ones on the same mount point, and max_by_key picks the last.
+    mounts
+        .iter()
+        .filter(|mount| path.starts_with(&mount.mount_point))
+        .max_by_key(|mount| mount.mount_point.components().count())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+23 22 0:22 / /proc rw,relatime - proc proc rw
+40 22 0:40 / /home/user/remote\\040dir rw,relatime shared:20 - fuse.sshfs host:/srv rw,user_id=1000
+41 22 0:41 / /mnt/nfs rw,relatime - nfs4 server:/export rw
+malformed
+";
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        assert_eq!(mounts.len(), 4);
+        assert_eq!(
+            mounts[2],
+            Mount {
+                id: 40,
+                mount_point: "/home/user/remote dir".into(),
+                fs_type: "fuse.sshfs".into(),
+                source: "host:/srv".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_changed_mount_points() {
+        let previous = parse_mountinfo(MOUNTINFO);
+        let mut current = previous.clone();
+        assert!(changed_mount_points(&previous, &current).is_empty());
+
+        // Mounted again.
+        current[3].id = 42;
+        current.push(Mount {
+            id: 43,
+            mount_point: "/mnt/usb".into(),
+            fs_type: "vfat".into(),
+            source: "/dev/sdb1".into(),
+        });
+        current.remove(1);
+        assert_eq!(
+            changed_mount_points(&previous, &current),
+            BTreeSet::from(["/mnt/nfs", "/mnt/usb", "/proc"].map(PathBuf::from))
+        );
+    }
+
+    #[test]
+    fn test_mount_of() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+
+        let mount = mount_of(&mounts, Path::new("/home/user/remote dir/file")).unwrap();
+        assert_eq!(mount.fs_type, "fuse.sshfs");
+        assert!(mount.is_remote());
+
+        let mount = mount_of(&mounts, Path::new("/home/user/remote")).unwrap();
+        assert_eq!(mount.fs_type, "ext4");
+        assert!(!mount.is_remote());
+
+        assert!(
+            mount_of(&mounts, Path::new("/mnt/nfs"))
+                .unwrap()
+                .is_remote()
+        );
+
+        // Mounted over each other, in both orders.
+        let stacked = parse_mountinfo(
+            "41 22 0:41 / /mnt/data rw - nfs4 server:/export rw\n\
+             42 41 8:2 / /mnt/data rw - ext4 /dev/sdb1 rw\n",
+        );
+        assert_eq!(
+            mount_of(&stacked, Path::new("/mnt/data/file"))
+                .unwrap()
+                .fs_type,
+            "ext4"
+        );
+        let stacked: Vec<Mount> = stacked.into_iter().rev().collect();
+        assert!(
+            mount_of(&stacked, Path::new("/mnt/data/file"))
+                .unwrap()
+                .is_remote()
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! Unicode normalization of reported paths, and case folding of matched ones.
+
+use failure::{Fallible, bail};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
+
+/// Form of names in paths reported to unison.
+///
+/// Replicas synced from macOS often hold decomposed names, while names typed on Linux are
+/// usually composed, so that the same name may be seen in either form.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Normalization {
+    /// Names as they are on disk.
+    #[default]
+    None,
+    /// Canonical composition.
+    Nfc,
+    /// Canonical decomposition.
+    Nfd,
+}
+
+impl FromStr for Normalization {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Fallible<Normalization> {
+        Ok(match s {
+            "none" => Normalization::None,
+            "nfc" => Normalization::Nfc,
+            "nfd" => Normalization::Nfd,
+            _ => bail!("Unknown normalization: {} (expected nfc, nfd or none)", s),
+        })
+    }
+}
+
+impl Normalization {
+    /// Normalizes the names of path. Names which aren't valid UTF-8 are kept as they are.
+    pub fn apply(self, path: &Path) -> PathBuf {
+        if self == Normalization::None {
+            return path.to_owned();
+        }
+        path.components()
+            .map(|component| match component.as_os_str().to_str() {
+                Some(name) if self == Normalization::Nfc => {
+                    OsString::from(name.nfc().collect::<String>())
+                }
+                Some(name) => OsString::from(name.nfd().collect::<String>()),
+                None => component.as_os_str().to_owned(),
+            })
+            .collect()
+    }
+
+    /// Path of relative under base as named on disk, where names of relative may be in another
+    /// form. Names not found on disk are kept as they are.
+    pub fn resolve(self, base: &Path, relative: &Path) -> PathBuf {
+        let mut path = base.to_owned();
+        for component in relative.components() {
+            let name = component.as_os_str();
+            if self != Normalization::None && path.join(name).symlink_metadata().is_err() {
+                let normalized = self.apply(Path::new(name));
+                let on_disk = fs::read_dir(&path).ok().and_then(|entries| {
+                    entries
+                        .flatten()
+                        .map(|entry| entry.file_name())
+                        .find(|entry| self.apply(Path::new(entry)) == normalized)
+                });
+                if let Some(on_disk) = on_disk {
+                    path.push(on_disk);
+                    continue;
+                }
+            }
+            path.push(name);
+        }
+        path
+    }
+}
+
+/// Folds the case of the names of path, for matching paths regardless of case. Names which aren't
+/// valid UTF-8 are kept as they are.
+pub fn fold_case(path: &Path) -> PathBuf {
+    path.components()
+        .map(|component| match component.as_os_str().to_str() {
+            Some(name) => OsString::from(name.to_lowercase()),
+            None => component.as_os_str().to_owned(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NFC: &str = "caf\u{e9}";
+    const NFD: &str = "cafe\u{301}";
+
+    #[test]
+    fn test_apply() {
+        let mixed = Path::new(NFC).join(NFD);
+
+        assert_eq!(Normalization::Nfc.apply(&mixed), Path::new(NFC).join(NFC));
+        assert_eq!(Normalization::Nfd.apply(&mixed), Path::new(NFD).join(NFD));
+        assert_eq!(Normalization::None.apply(&mixed), mixed);
+        assert_eq!(
+            Normalization::Nfc.apply(Path::new("/tmp/sample")),
+            Path::new("/tmp/sample")
+        );
+        assert_eq!(Normalization::Nfc.apply(Path::new("")), Path::new(""));
+        assert_eq!("nfd".parse::<Normalization>().unwrap(), Normalization::Nfd);
+        assert!("nfkc".parse::<Normalization>().is_err());
+    }
+
+    #[test]
+    fn test_fold_case() {
+        assert_eq!(
+            fold_case(Path::new("/Tmp/CAF\u{c9}/Sample")),
+            Path::new("/tmp/caf\u{e9}/sample")
+        );
+        assert_eq!(fold_case(Path::new("")), Path::new(""));
+    }
+}
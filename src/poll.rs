@@ -0,0 +1,402 @@
+//! Polling backend, for filesystems where changes don't produce events, e.g., NFS or sshfs.
+//!
+//! Every interval, all known directories are checked with a single `stat`, and only those whose
+//! modification time changed are listed again to find created and removed entries. Files are
+//! checked for modifications in batches, round-robin, so that a cycle never stats the whole tree.
+
+use crate::event::{EventKind, FsEvent};
+use crate::watch::Watch;
+use failure::{Fallible, ResultExt};
+use log::debug;
+use notify::RecursiveMode;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::fs;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Default interval between polls.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Default number of files checked for modifications per poll.
+pub const DEFAULT_BATCH: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FileStat {
+    mtime: Option<SystemTime>,
+    len: u64,
+}
+
+impl FileStat {
+    fn new(metadata: &fs::Metadata) -> Self {
+        Self {
+            mtime: metadata.modified().ok(),
+            len: metadata.len(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Dir {
+    /// `None` if the directory doesn't exist.
+    mtime: Option<SystemTime>,
+    entries: BTreeSet<OsString>,
+}
+
+/// Known state of a watched root.
+#[derive(Debug)]
+struct Snapshot {
+    root: PathBuf,
+    recursive: bool,
+    dirs: BTreeMap<PathBuf, Dir>,
+    files: BTreeMap<PathBuf, FileStat>,
+    /// Next file to be checked for modifications.
+    cursor: Option<PathBuf>,
+}
+
+impl Snapshot {
+    fn new(root: &Path, recursive: bool) -> Self {
+        let mut snapshot = Self {
+            root: root.to_owned(),
+            recursive,
+            dirs: BTreeMap::new(),
+            files: BTreeMap::new(),
+            cursor: None,
+        };
+        snapshot.add_dir(root, &mut None);
+        snapshot
+    }
+
+    /// Records directory along with its entries. Created entries are reported to events, if any.
+    fn add_dir(&mut self, dir: &Path, events: &mut Option<&mut Vec<FsEvent>>) {
+        let mtime = fs::metadata(dir).and_then(|m| m.modified()).ok();
+        self.dirs.insert(
+            dir.to_owned(),
+            Dir {
+                mtime,
+                entries: BTreeSet::new(),
+            },
+        );
+        for name in list(dir) {
+            self.add_entry(dir, name, events);
+        }
+    }
+
+    fn add_entry(&mut self, dir: &Path, name: OsString, events: &mut Option<&mut Vec<FsEvent>>) {
+        let path = dir.join(&name);
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            return;
+        };
+        if let Some(dir) = self.dirs.get_mut(dir) {
+            dir.entries.insert(name);
+        }
+        if let Some(events) = events {
+            events.push(FsEvent::new(EventKind::Create, &path));
+        }
+        if metadata.is_dir() && self.recursive {
+            self.add_dir(&path, events);
+        } else {
+            self.files.insert(path, FileStat::new(&metadata));
+        }
+    }
+
+    /// Forgets path and everything under it.
+    fn remove(&mut self, path: &Path) {
+        let under: Vec<PathBuf> = range_under(&self.dirs, path)
+            .chain(range_under(&self.files, path))
+            .collect();
+        for path in under {
+            self.dirs.remove(&path);
+            self.files.remove(&path);
+        }
+    }
+
+    /// Checks directories, and a batch of files, for changes since the last scan.
+    fn scan(&mut self, batch: usize, events: &mut Vec<FsEvent>) {
+        let dirs: Vec<PathBuf> = self.dirs.keys().cloned().collect();
+        for dir in dirs {
+            let Some(known) = self.dirs.get(&dir) else {
+                // Removed earlier in this scan.
+                continue;
+            };
+            let mtime = fs::metadata(&dir)
+                .ok()
+                .filter(|m| m.is_dir())
+                .and_then(|m| m.modified().ok());
+            if mtime != known.mtime {
+                self.rescan_dir(&dir, mtime, events);
+            }
+        }
+
+        let cursor = self.cursor.take();
+        let start = match &cursor {
+            Some(cursor) => Bound::Included(cursor.as_path()),
+            None => Bound::Unbounded,
+        };
+        let mut batch_paths: Vec<PathBuf> = self
+            .files
+            .range::<Path, _>((start, Bound::Unbounded))
+            .map(|(path, _)| path.clone())
+            .take(batch + 1)
+            .collect();
+        if batch_paths.len() > batch {
+            self.cursor = batch_paths.pop();
+        }
+        for path in batch_paths {
+            let stat = fs::symlink_metadata(&path).ok().map(|m| FileStat::new(&m));
+            if let (Some(stat), Some(known)) = (stat, self.files.get_mut(&path))
+                && stat != *known
+            {
+                *known = stat;
+                events.push(FsEvent::new(EventKind::ModifyData, &path));
+            }
+            // Removed files are found by listing their directory.
+        }
+    }
+
+    /// Lists directory again, reporting created and removed entries.
+    fn rescan_dir(&mut self, dir: &Path, mtime: Option<SystemTime>, events: &mut Vec<FsEvent>) {
+        if mtime.is_none() && dir == self.root {
+            // Root went away, keep checking for its return.
+            debug!("Root removed: {:?}", dir);
+            events.push(FsEvent::new(EventKind::Remove, dir));
+            self.dirs.clear();
+            self.files.clear();
+            self.dirs.insert(dir.to_owned(), Dir::default());
+            return;
+        }
+        if self.dirs.get(dir).is_some_and(|d| d.mtime.is_none()) && mtime.is_some() {
+            debug!("Root created: {:?}", dir);
+            events.push(FsEvent::new(EventKind::Create, dir));
+        }
+
+        let current: BTreeSet<OsString> = list(dir).into_iter().collect();
+        let known = match self.dirs.get_mut(dir) {
+            Some(known) => {
+                known.mtime = mtime;
+                std::mem::take(&mut known.entries)
+            }
+            None => return,
+        };
+
+        for name in known.difference(&current) {
+            let path = dir.join(name);
+            events.push(FsEvent::new(EventKind::Remove, &path));
+            self.remove(&path);
+        }
+        for name in current.difference(&known).cloned() {
+            self.add_entry(dir, name, &mut Some(events));
+        }
+        if let Some(known) = self.dirs.get_mut(dir) {
+            known.entries.extend(current);
+        }
+    }
+}
+
+fn list(dir: &Path) -> Vec<OsString> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.file_name()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn range_under<'a, T>(
+    map: &'a BTreeMap<PathBuf, T>,
+    path: &'a Path,
+) -> impl Iterator<Item = PathBuf> + 'a {
+    map.range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+        .map(|(p, _)| p)
+        .take_while(move |p| p.starts_with(path))
+        .cloned()
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Snapshots by watched path, `None` while being scanned.
+    snapshots: HashMap<PathBuf, Option<Snapshot>>,
+}
+
+fn lock(state: &Mutex<State>) -> std::sync::MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Watches paths by polling them every interval, sending events to a channel.
+pub struct PollWatcher {
+    state: Arc<Mutex<State>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl PollWatcher {
+    /// Starts polling in the background, checking at most batch files for modifications every
+    /// interval.
+    pub fn new(tx: Sender<FsEvent>, interval: Duration, batch: usize) -> Self {
+        let state: Arc<Mutex<State>> = Arc::default();
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let state_clone = state.clone();
+        let stopped_clone = stopped.clone();
+        thread::spawn(move || {
+            while !stopped_clone.load(Ordering::Relaxed) {
+                thread::sleep(interval);
+
+                let mut events = vec![];
+                let paths: Vec<PathBuf> = lock(&state_clone).snapshots.keys().cloned().collect();
+                for path in paths {
+                    // Scanned without holding the lock, which watch and unwatch wait for.
+                    let Some(mut snapshot) = lock(&state_clone)
+                        .snapshots
+                        .get_mut(&path)
+                        .and_then(Option::take)
+                    else {
+                        continue;
+                    };
+                    let mut scanned = vec![];
+                    snapshot.scan(batch, &mut scanned);
+                    // Dropped if unwatched or watched anew in the meantime.
+                    if let Some(slot @ None) = lock(&state_clone).snapshots.get_mut(&path) {
+                        *slot = Some(snapshot);
+                        events.extend(scanned);
+                    }
+                }
+                for event in events {
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Self { state, stopped }
+    }
+}
+
+impl Drop for PollWatcher {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Watch for PollWatcher {
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Fallible<()> {
+        fs::metadata(path).with_context(|e| format!("Unable to poll path={:?}: {}", path, e))?;
+        let snapshot = Snapshot::new(path, recursive_mode == RecursiveMode::Recursive);
+        debug!(
+            "Polling {:?}: {} dirs, {} files",
+            path,
+            snapshot.dirs.len(),
+            snapshot.files.len()
+        );
+        lock(&self.state)
+            .snapshots
+            .insert(path.to_owned(), Some(snapshot));
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Fallible<()> {
+        lock(&self.state).snapshots.remove(path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scan(snapshot: &mut Snapshot, batch: usize) -> Vec<(PathBuf, EventKind)> {
+        let mut events = vec![];
+        snapshot.scan(batch, &mut events);
+        let mut events: Vec<(PathBuf, EventKind)> = events
+            .into_iter()
+            .map(|e| (e.paths[0].clone(), e.kind))
+            .collect();
+        events.sort_by(|a, b| a.0.cmp(&b.0));
+        events
+    }
+
+    /// Directory modification times may have a coarse granularity.
+    fn tick() {
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_scan() {
+        let root =
+            std::env::temp_dir().join(format!("unison-fsmonitor-poll-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("subdir")).unwrap();
+        fs::write(root.join("subdir/a"), "a").unwrap();
+
+        let mut snapshot = Snapshot::new(&root, true);
+        assert!(scan(&mut snapshot, 10).is_empty());
+
+        tick();
+        fs::create_dir(root.join("new")).unwrap();
+        fs::write(root.join("new/b"), "b").unwrap();
+        assert_eq!(
+            scan(&mut snapshot, 10),
+            [
+                (root.join("new"), EventKind::Create),
+                (root.join("new/b"), EventKind::Create)
+            ]
+        );
+
+        tick();
+        fs::write(root.join("subdir/a"), "modified").unwrap();
+        assert_eq!(
+            scan(&mut snapshot, 10),
+            [(root.join("subdir/a"), EventKind::ModifyData)]
+        );
+
+        tick();
+        fs::remove_dir_all(root.join("subdir")).unwrap();
+        assert_eq!(
+            scan(&mut snapshot, 10),
+            [(root.join("subdir"), EventKind::Remove)]
+        );
+        assert!(!snapshot.dirs.contains_key(&root.join("subdir")));
+        assert!(!snapshot.files.contains_key(&root.join("subdir/a")));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_scan_in_batches() {
+        let root =
+            std::env::temp_dir().join(format!("unison-fsmonitor-batch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(root.join(name), "").unwrap();
+        }
+
+        let mut snapshot = Snapshot::new(&root, true);
+        tick();
+        for name in ["a", "b", "c"] {
+            fs::write(root.join(name), "modified").unwrap();
+        }
+
+        // Only two files are checked per scan.
+        assert_eq!(
+            scan(&mut snapshot, 2),
+            [
+                (root.join("a"), EventKind::ModifyData),
+                (root.join("b"), EventKind::ModifyData)
+            ]
+        );
+        assert_eq!(
+            scan(&mut snapshot, 2),
+            [(root.join("c"), EventKind::ModifyData)]
+        );
+        assert!(scan(&mut snapshot, 2).is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,442 @@
+//! Wire format of the unison fsmonitor protocol.
+//!
+//! Every message is a single line made of a command followed by space separated arguments.
+//! Arguments are percent-encoded so that they never contain whitespace.
+
+use failure::{Fallible, bail};
+use std::fmt;
+use std::path::PathBuf;
+
+/// Replica identifier, as chosen by unison.
+pub type Id = String;
+
+/// Protocol versions supported, in increasing order.
+pub const VERSIONS: &[u32] = &[1];
+
+/// Optional protocol extensions supported, enabled only when offered by unison as well.
+pub const CAPABILITIES: &[&str] = &[KEEPALIVE];
+
+/// Capability of exchanging `KEEPALIVE` messages, see [`Request::Keepalive`].
+pub const KEEPALIVE: &str = "keepalive";
+
+/// Request sent by unison.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// `VERSION <version>... [<capability>]...`, protocol versions and extensions supported by
+    /// unison. Unison itself only sends a single version.
+    Version {
+        versions: Vec<u32>,
+        capabilities: Vec<String>,
+    },
+    /// `DEBUG`, turn on debugging output.
+    Debug,
+    /// `START <replica> <root> [<subdir>]`, start watching root, or only subdir of root.
+    Start {
+        replica: Id,
+        root: PathBuf,
+        subdir: Option<PathBuf>,
+    },
+    /// `DIR [<path>]`, a directory under the last started path.
+    Dir(Option<PathBuf>),
+    /// `LINK [<path>]`, a symbolic link under the last started path to be followed.
+    Link(Option<PathBuf>),
+    /// `WAIT <replica>`, unison waits for changes of replica.
+    Wait { replica: Id },
+    /// `CHANGES <replica>`, unison requests pending changes of replica.
+    Changes { replica: Id },
+    /// `RESET <replica>`, stop watching replica.
+    Reset { replica: Id },
+    /// `DONE`, end of a START/DIR/LINK sequence.
+    Done,
+    /// `KEEPALIVE`, unison is alive. Only with the [`KEEPALIVE`] capability.
+    Keepalive,
+}
+
+/// Response sent to unison.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// `VERSION <version> [<capability>]...`, negotiated protocol version and extensions.
+    Version {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    /// `OK`, acknowledges START, DIR and LINK.
+    Ok,
+    /// `CHANGES <replica>`, replica waited on has pending changes.
+    Changes { replica: Id },
+    /// `RECURSIVE <path>`, path relative to replica root changed, possibly recursively.
+    Recursive(PathBuf),
+    /// `DONE`, end of pending changes.
+    Done,
+    /// `ERROR <message>`, fatal error.
+    Error(String),
+    /// `KEEPALIVE`, the monitor is alive. Only with the [`KEEPALIVE`] capability.
+    Keepalive,
+}
+
+/// Percent-encodes a single argument.
+pub fn encode(s: &str) -> impl AsRef<str> {
+    percent_encoding::utf8_percent_encode(s, percent_encoding::NON_ALPHANUMERIC).to_string()
+}
+
+/// Decodes a single percent-encoded argument.
+pub fn decode<'a>(s: &'a str) -> impl AsRef<str> + 'a {
+    percent_encoding::percent_decode(s.as_bytes()).decode_utf8_lossy()
+}
+
+/// Parses a line into command and decoded arguments.
+pub fn parse_input(input: &str) -> Fallible<(String, Vec<String>)> {
+    let mut cmd = String::new();
+    let mut args = vec![];
+    for (idx, word) in input.split_whitespace().enumerate() {
+        if idx == 0 {
+            cmd = word.to_owned();
+        } else {
+            args.push(decode(word).as_ref().to_owned())
+        }
+    }
+    Ok((cmd, args))
+}
+
+/// Serializes command and arguments into a line, without the trailing newline.
+pub fn serialize(cmd: &str, args: &[&str]) -> String {
+    let mut output = cmd.to_owned();
+    for arg in args {
+        output += " ";
+        output += encode(arg).as_ref();
+    }
+    output
+}
+
+/// Checks that cmd has between min and max arguments.
+fn check_args(cmd: &str, args: Vec<String>, min: usize, max: usize) -> Fallible<Args> {
+    if args.len() < min || args.len() > max {
+        bail!("Unexpected number of arguments for {}: {:?}", cmd, args);
+    }
+    Ok(args.into_iter())
+}
+
+type Args = std::vec::IntoIter<String>;
+
+/// Takes the next argument, which must exist as ensured by [`check_args`].
+fn required(args: &mut Args) -> String {
+    args.next().unwrap_or_default()
+}
+
+/// Splits VERSION arguments into numeric versions and capabilities.
+fn split_versions(args: Args) -> (Vec<u32>, Vec<String>) {
+    let mut versions = vec![];
+    let mut capabilities = vec![];
+    for arg in args {
+        match arg.parse() {
+            Ok(version) => versions.push(version),
+            Err(_) => capabilities.push(arg),
+        }
+    }
+    (versions, capabilities)
+}
+
+/// Picks the highest version supported by both sides, along with the common capabilities.
+/// Returns `None` if there isn't any common version.
+pub fn negotiate(versions: &[u32], capabilities: &[String]) -> Option<(u32, Vec<String>)> {
+    let version = versions
+        .iter()
+        .filter(|version| VERSIONS.contains(version))
+        .max()?;
+    let capabilities = capabilities
+        .iter()
+        .filter(|capability| CAPABILITIES.contains(&capability.as_str()))
+        .cloned()
+        .collect();
+    Some((*version, capabilities))
+}
+
+impl Request {
+    /// Parses a line sent by unison.
+    pub fn parse(line: &str) -> Fallible<Request> {
+        let (cmd, args) = parse_input(line)?;
+        let request = match cmd.as_str() {
+            "VERSION" => {
+                let (versions, capabilities) =
+                    split_versions(check_args(&cmd, args, 1, usize::MAX)?);
+                Request::Version {
+                    versions,
+                    capabilities,
+                }
+            }
+            "DEBUG" => {
+                check_args(&cmd, args, 0, 0)?;
+                Request::Debug
+            }
+            "START" => {
+                let mut args = check_args(&cmd, args, 2, 3)?;
+                Request::Start {
+                    replica: required(&mut args),
+                    root: required(&mut args).into(),
+                    subdir: args.next().map(PathBuf::from),
+                }
+            }
+            "DIR" => Request::Dir(check_args(&cmd, args, 0, 1)?.next().map(PathBuf::from)),
+            "LINK" => Request::Link(check_args(&cmd, args, 0, 1)?.next().map(PathBuf::from)),
+            "WAIT" => Request::Wait {
+                replica: required(&mut check_args(&cmd, args, 1, 1)?),
+            },
+            "CHANGES" => Request::Changes {
+                replica: required(&mut check_args(&cmd, args, 1, 1)?),
+            },
+            "RESET" => Request::Reset {
+                replica: required(&mut check_args(&cmd, args, 1, 1)?),
+            },
+            "DONE" => {
+                check_args(&cmd, args, 0, 0)?;
+                Request::Done
+            }
+            "KEEPALIVE" => {
+                check_args(&cmd, args, 0, 0)?;
+                Request::Keepalive
+            }
+            _ => bail!("Unrecognized cmd: {}", cmd),
+        };
+        Ok(request)
+    }
+
+    fn to_parts(&self) -> (&'static str, Vec<String>) {
+        let path = |p: &PathBuf| p.to_string_lossy().into_owned();
+        match self {
+            Request::Version {
+                versions,
+                capabilities,
+            } => {
+                let mut args: Vec<String> = versions.iter().map(u32::to_string).collect();
+                args.extend(capabilities.iter().cloned());
+                ("VERSION", args)
+            }
+            Request::Debug => ("DEBUG", vec![]),
+            Request::Start {
+                replica,
+                root,
+                subdir,
+            } => {
+                let mut args = vec![replica.clone(), path(root)];
+                args.extend(subdir.iter().map(path));
+                ("START", args)
+            }
+            Request::Dir(dir) => ("DIR", dir.iter().map(path).collect()),
+            Request::Link(link) => ("LINK", link.iter().map(path).collect()),
+            Request::Wait { replica } => ("WAIT", vec![replica.clone()]),
+            Request::Changes { replica } => ("CHANGES", vec![replica.clone()]),
+            Request::Reset { replica } => ("RESET", vec![replica.clone()]),
+            Request::Done => ("DONE", vec![]),
+            Request::Keepalive => ("KEEPALIVE", vec![]),
+        }
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (cmd, args) = self.to_parts();
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        write!(f, "{}", serialize(cmd, &args))
+    }
+}
+
+impl Response {
+    /// Parses a line sent to unison.
+    pub fn parse(line: &str) -> Fallible<Response> {
+        let (cmd, args) = parse_input(line)?;
+        let response = match cmd.as_str() {
+            "VERSION" => {
+                let (versions, capabilities) =
+                    split_versions(check_args(&cmd, args, 1, usize::MAX)?);
+                match versions.as_slice() {
+                    [version] => Response::Version {
+                        version: *version,
+                        capabilities,
+                    },
+                    _ => bail!("Expected a single version: {:?}", versions),
+                }
+            }
+            "OK" => {
+                check_args(&cmd, args, 0, 0)?;
+                Response::Ok
+            }
+            "CHANGES" => Response::Changes {
+                replica: required(&mut check_args(&cmd, args, 1, 1)?),
+            },
+            // The root of a replica is sent as an empty path.
+            "RECURSIVE" => Response::Recursive(
+                check_args(&cmd, args, 0, 1)?
+                    .next()
+                    .unwrap_or_default()
+                    .into(),
+            ),
+            "DONE" => {
+                check_args(&cmd, args, 0, 0)?;
+                Response::Done
+            }
+            "KEEPALIVE" => {
+                check_args(&cmd, args, 0, 0)?;
+                Response::Keepalive
+            }
+            "ERROR" => Response::Error(check_args(&cmd, args, 0, 1)?.next().unwrap_or_default()),
+            _ => bail!("Unrecognized cmd: {}", cmd),
+        };
+        Ok(response)
+    }
+
+    fn to_parts(&self) -> (&'static str, Vec<String>) {
+        match self {
+            Response::Version {
+                version,
+                capabilities,
+            } => {
+                let mut args = vec![version.to_string()];
+                args.extend(capabilities.iter().cloned());
+                ("VERSION", args)
+            }
+            Response::Ok => ("OK", vec![]),
+            Response::Changes { replica } => ("CHANGES", vec![replica.clone()]),
+            Response::Recursive(path) => ("RECURSIVE", vec![path.to_string_lossy().into_owned()]),
+            Response::Done => ("DONE", vec![]),
+            Response::Error(msg) => ("ERROR", vec![msg.clone()]),
+            Response::Keepalive => ("KEEPALIVE", vec![]),
+        }
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (cmd, args) = self.to_parts();
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        write!(f, "{}", serialize(cmd, &args))
+    }
+}
+
+#[test]
+fn test_encode() {
+    assert_eq!(encode("before%after").as_ref(), "before%25after");
+}
+
+#[test]
+fn test_round_trip() {
+    let line = serialize("START", &["123", "/tmp/sample dir", "sub/dir"]);
+    assert_eq!(line, "START 123 %2Ftmp%2Fsample%20dir sub%2Fdir");
+    assert_eq!(
+        parse_input(&line).unwrap(),
+        (
+            "START".to_owned(),
+            vec![
+                "123".to_owned(),
+                "/tmp/sample dir".to_owned(),
+                "sub/dir".to_owned()
+            ]
+        )
+    );
+}
+
+#[test]
+fn test_request_round_trip() {
+    let requests = vec![
+        Request::Version {
+            versions: vec![1],
+            capabilities: vec![],
+        },
+        Request::Version {
+            versions: vec![1, 2],
+            capabilities: vec!["extension".into()],
+        },
+        Request::Debug,
+        Request::Start {
+            replica: "123".into(),
+            root: "/tmp/sample dir".into(),
+            subdir: None,
+        },
+        Request::Start {
+            replica: "123".into(),
+            root: "/tmp/sample".into(),
+            subdir: Some("sub/dir".into()),
+        },
+        Request::Dir(None),
+        Request::Dir(Some("dir".into())),
+        Request::Link(Some("link".into())),
+        Request::Wait {
+            replica: "123".into(),
+        },
+        Request::Changes {
+            replica: "123".into(),
+        },
+        Request::Reset {
+            replica: "123".into(),
+        },
+        Request::Done,
+        Request::Keepalive,
+    ];
+    for request in requests {
+        assert_eq!(Request::parse(&request.to_string()).unwrap(), request);
+    }
+}
+
+#[test]
+fn test_response_round_trip() {
+    let responses = vec![
+        Response::Version {
+            version: 1,
+            capabilities: vec![],
+        },
+        Response::Version {
+            version: 1,
+            capabilities: vec!["extension".into()],
+        },
+        Response::Ok,
+        Response::Changes {
+            replica: "123".into(),
+        },
+        Response::Recursive("sub dir/filename".into()),
+        Response::Recursive("".into()),
+        Response::Done,
+        Response::Error("Unknown replica: 123".into()),
+        Response::Keepalive,
+    ];
+    for response in responses {
+        assert_eq!(Response::parse(&response.to_string()).unwrap(), response);
+    }
+}
+
+#[test]
+fn test_parse_request() {
+    assert_eq!(
+        Request::parse("START 123 %2Ftmp%2Fsample subdir\n").unwrap(),
+        Request::Start {
+            replica: "123".into(),
+            root: "/tmp/sample".into(),
+            subdir: Some("subdir".into()),
+        }
+    );
+    assert_eq!(
+        Request::parse("VERSION 2 1 extension").unwrap(),
+        Request::Version {
+            versions: vec![2, 1],
+            capabilities: vec!["extension".into()],
+        }
+    );
+    assert!(Request::parse("VERSION").is_err());
+    assert!(Request::parse("START 123").is_err());
+    assert!(Request::parse("WAIT").is_err());
+    assert!(Request::parse("WAIT 123 456").is_err());
+    assert!(Request::parse("DONE 123").is_err());
+    assert!(Request::parse("UNKNOWN").is_err());
+    assert!(Request::parse("").is_err());
+}
+
+#[test]
+fn test_negotiate() {
+    assert_eq!(negotiate(&[1], &[]), Some((1, vec![])));
+    assert_eq!(negotiate(&[1, 99], &[]), Some((1, vec![])));
+    assert_eq!(negotiate(&[1], &["unknown".into()]), Some((1, vec![])));
+    assert_eq!(
+        negotiate(&[1], &[KEEPALIVE.into()]),
+        Some((1, vec![KEEPALIVE.into()]))
+    );
+    assert_eq!(negotiate(&[99], &[]), None);
+    assert_eq!(negotiate(&[], &[]), None);
+}
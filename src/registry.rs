@@ -0,0 +1,433 @@
+//! Reference counted registry of watches.
+//!
+//! Replicas and followed links request watches on paths, possibly overlapping. The registry keeps
+//! track of who depends on each path and keeps the minimal set of backend watches covering all
+//! requested paths: a path under a recursively watched path doesn't get a watch of its own.
+//! Backend watches never overlap, as removing a recursive watch also removes the watches under
+//! it with some backends.
+
+use crate::limits::{is_watch_limit, watch_limit_diagnostic};
+use crate::watch::Watch;
+use failure::Fallible;
+use log::{debug, error, warn};
+use notify::RecursiveMode;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+/// Watches requested by dependents of type `D`, e.g., replicas.
+#[derive(Debug)]
+pub struct WatchRegistry<D> {
+    /// Requested paths along with their dependents. Ordered so that the descendants of a path
+    /// directly follow it.
+    requested: BTreeMap<PathBuf, HashMap<D, RecursiveMode>>,
+    /// Watches in place in the backend.
+    active: BTreeMap<PathBuf, RecursiveMode>,
+    /// Requested paths whose backend watch was lost, not watched until restored.
+    lost: HashSet<PathBuf>,
+}
+
+impl<D> Default for WatchRegistry<D> {
+    fn default() -> Self {
+        Self {
+            requested: BTreeMap::new(),
+            active: BTreeMap::new(),
+            lost: HashSet::new(),
+        }
+    }
+}
+
+impl<D: Clone + Eq + Hash + std::fmt::Debug> WatchRegistry<D> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests watching path on behalf of dependent.
+    pub fn add<WATCH: Watch + ?Sized>(
+        &mut self,
+        watcher: &mut WATCH,
+        path: &Path,
+        recursive_mode: RecursiveMode,
+        dependent: D,
+    ) -> Fallible<()> {
+        let previous = self
+            .requested
+            .entry(path.to_owned())
+            .or_default()
+            .insert(dependent.clone(), recursive_mode);
+
+        if let Err(e) = self.sync(watcher, path) {
+            // Roll back.
+            let dependents = self.requested.entry(path.to_owned()).or_default();
+            match previous {
+                Some(recursive_mode) => dependents.insert(dependent, recursive_mode),
+                None => dependents.remove(&dependent),
+            };
+            if dependents.is_empty() {
+                self.requested.remove(path);
+            }
+            if let Err(e) = self.sync(watcher, path) {
+                warn!("Unable to restore watches: {}", e);
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Drops the request of dependent on path.
+    pub fn remove<WATCH: Watch + ?Sized>(
+        &mut self,
+        watcher: &mut WATCH,
+        path: &Path,
+        dependent: &D,
+    ) -> Fallible<()> {
+        if let Some(dependents) = self.requested.get_mut(path) {
+            dependents.remove(dependent);
+            if dependents.is_empty() {
+                self.requested.remove(path);
+                self.lost.remove(path);
+            }
+        }
+        self.sync(watcher, path)
+    }
+
+    /// Drops all requests of dependent.
+    pub fn remove_dependent<WATCH: Watch + ?Sized>(
+        &mut self,
+        watcher: &mut WATCH,
+        dependent: &D,
+    ) -> Fallible<()> {
+        let paths: Vec<PathBuf> = self.paths_of(dependent).map(Path::to_owned).collect();
+        let mut result = Ok(());
+        for path in paths {
+            if let Err(e) = self.remove(watcher, &path, dependent) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Drops the backend watch of path after the backend lost it, e.g., as path was deleted.
+    /// Requests are kept, see [`WatchRegistry::restore`].
+    pub fn forget<WATCH: Watch + ?Sized>(&mut self, watcher: &mut WATCH, path: &Path) {
+        self.lost.insert(path.to_owned());
+        if self.active.remove(path).is_some() {
+            debug!("forget: {:?}", path);
+            if let Err(e) = watcher.unwatch(path) {
+                debug!("Unable to unwatch lost path={:?}: {}", path, e);
+            }
+        }
+    }
+
+    /// Watches path again once it is back, after [`WatchRegistry::forget`].
+    pub fn restore<WATCH: Watch + ?Sized>(
+        &mut self,
+        watcher: &mut WATCH,
+        path: &Path,
+    ) -> Fallible<()> {
+        self.lost.remove(path);
+        if let Err(e) = self.sync(watcher, path) {
+            self.lost.insert(path.to_owned());
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// All requested paths.
+    pub fn requested_paths(&self) -> impl Iterator<Item = &Path> {
+        self.requested.keys().map(PathBuf::as_path)
+    }
+
+    /// Dependents which requested path.
+    pub fn dependents<'a>(&'a self, path: &Path) -> impl Iterator<Item = &'a D> + 'a {
+        self.requested.get(path).into_iter().flat_map(HashMap::keys)
+    }
+
+    /// Check if path is covered by a backend watch.
+    pub fn is_watching(&self, path: &Path) -> bool {
+        path.ancestors().any(|base| match self.active.get(base) {
+            Some(RecursiveMode::Recursive) => true,
+            Some(RecursiveMode::NonRecursive) => base == path,
+            None => false,
+        })
+    }
+
+    /// Paths requested by dependent.
+    pub fn paths_of<'a>(&'a self, dependent: &'a D) -> impl Iterator<Item = &'a Path> + 'a {
+        self.requested
+            .iter()
+            .filter(move |(_, dependents)| dependents.contains_key(dependent))
+            .map(|(path, _)| path.as_path())
+    }
+
+    /// Number of backend watches.
+    pub fn len(&self) -> usize {
+        self.active.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+
+    /// Mode of the backend watch path needs, if any. Paths under a recursively requested path
+    /// don't need one, nor do lost paths.
+    fn desired(&self, path: &Path) -> Option<RecursiveMode> {
+        if self.lost.contains(path) {
+            return None;
+        }
+        let mode_of = |path: &Path| {
+            self.requested.get(path).map(|dependents| {
+                if dependents
+                    .values()
+                    .any(|mode| *mode == RecursiveMode::Recursive)
+                {
+                    RecursiveMode::Recursive
+                } else {
+                    RecursiveMode::NonRecursive
+                }
+            })
+        };
+
+        if path
+            .ancestors()
+            .skip(1)
+            .any(|base| mode_of(base) == Some(RecursiveMode::Recursive))
+        {
+            return None;
+        }
+        mode_of(path)
+    }
+
+    /// Brings backend watches of path and its descendants in line with requested paths. New
+    /// watches are added before stale ones are removed, so that paths moving from one watch to
+    /// another stay watched. Backends have to cope with watches overlapping meanwhile.
+    fn sync<WATCH: Watch + ?Sized>(&mut self, watcher: &mut WATCH, scope: &Path) -> Fallible<()> {
+        let under_scope = |path: &&PathBuf| path.starts_with(scope);
+        let mut paths: Vec<PathBuf> = self
+            .requested
+            .range::<Path, _>((Bound::Included(scope), Bound::Unbounded))
+            .map(|(path, _)| path)
+            .take_while(under_scope)
+            .chain(
+                self.active
+                    .range::<Path, _>((Bound::Included(scope), Bound::Unbounded))
+                    .map(|(path, _)| path)
+                    .take_while(under_scope),
+            )
+            .cloned()
+            .collect();
+        paths.sort();
+        paths.dedup();
+
+        let desired: Vec<(PathBuf, Option<RecursiveMode>)> = paths
+            .into_iter()
+            .map(|path| {
+                let desired = self.desired(&path);
+                (path, desired)
+            })
+            .collect();
+
+        // Watches changing mode are replaced, as backends watch a path only once.
+        for (path, recursive_mode) in &desired {
+            if let Some(active) = self.active.get(path)
+                && recursive_mode.is_some_and(|mode| mode != *active)
+            {
+                debug!("unwatch: {:?}", path);
+                self.active.remove(path);
+                if let Err(e) = watcher.unwatch(path) {
+                    warn!("Unable to unwatch path={:?}: {}", path, e);
+                }
+            }
+        }
+
+        let mut result = Ok(());
+        for (path, recursive_mode) in &desired {
+            let Some(recursive_mode) = *recursive_mode else {
+                continue;
+            };
+            if self.active.contains_key(path) {
+                continue;
+            }
+            debug!("watch: {:?} {:?}", path, recursive_mode);
+            match watcher.watch(path, recursive_mode) {
+                Ok(()) => {
+                    self.active.insert(path.clone(), recursive_mode);
+                }
+                Err(e) if is_watch_limit(&e) => {
+                    error!("{}", watch_limit_diagnostic(path));
+                    match watcher.poll(path, recursive_mode) {
+                        Ok(()) => {
+                            warn!("Polling {:?} instead", path);
+                            self.active.insert(path.clone(), recursive_mode);
+                        }
+                        Err(poll_error) => {
+                            debug!("{}", poll_error);
+                            result = Err(e);
+                        }
+                    }
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+
+        for (path, recursive_mode) in &desired {
+            if recursive_mode.is_none() && self.active.remove(path).is_some() {
+                debug!("unwatch: {:?}", path);
+                if let Err(e) = watcher.unwatch(path) {
+                    warn!("Unable to unwatch path={:?}: {}", path, e);
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use failure::bail;
+
+    /// Records backend calls.
+    #[derive(Default)]
+    struct Watcher {
+        calls: Vec<String>,
+    }
+
+    impl Watch for Watcher {
+        fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Fallible<()> {
+            if path.ends_with("missing") {
+                bail!("No such file or directory");
+            }
+            self.calls
+                .push(format!("watch {} {:?}", path.display(), recursive_mode));
+            Ok(())
+        }
+
+        fn unwatch(&mut self, path: &Path) -> Fallible<()> {
+            self.calls.push(format!("unwatch {}", path.display()));
+            Ok(())
+        }
+    }
+
+    impl Watcher {
+        fn take(&mut self) -> Vec<String> {
+            std::mem::take(&mut self.calls)
+        }
+    }
+
+    #[test]
+    fn test_shared_path() {
+        let mut watcher = Watcher::default();
+        let mut registry = WatchRegistry::new();
+        let path = Path::new("/tmp/sample");
+
+        registry
+            .add(&mut watcher, path, RecursiveMode::Recursive, 1)
+            .unwrap();
+        registry
+            .add(&mut watcher, path, RecursiveMode::Recursive, 2)
+            .unwrap();
+        assert_eq!(watcher.take(), ["watch /tmp/sample Recursive"]);
+
+        registry.remove(&mut watcher, path, &1).unwrap();
+        assert!(watcher.take().is_empty());
+        assert!(registry.is_watching(&path.join("filename")));
+
+        registry.remove_dependent(&mut watcher, &2).unwrap();
+        assert_eq!(watcher.take(), ["unwatch /tmp/sample"]);
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn test_nested_paths() {
+        let mut watcher = Watcher::default();
+        let mut registry = WatchRegistry::new();
+        let parent = Path::new("/tmp/sample");
+        let child = Path::new("/tmp/sample/subdir");
+
+        registry
+            .add(&mut watcher, child, RecursiveMode::Recursive, 1)
+            .unwrap();
+        assert_eq!(watcher.take(), ["watch /tmp/sample/subdir Recursive"]);
+
+        // Child watch is dropped once parent is watched.
+        registry
+            .add(&mut watcher, parent, RecursiveMode::Recursive, 2)
+            .unwrap();
+        assert_eq!(
+            watcher.take(),
+            ["watch /tmp/sample Recursive", "unwatch /tmp/sample/subdir"]
+        );
+        assert_eq!(registry.len(), 1);
+
+        // Child is still watched on its own once parent goes away.
+        registry.remove_dependent(&mut watcher, &2).unwrap();
+        assert_eq!(
+            watcher.take(),
+            ["watch /tmp/sample/subdir Recursive", "unwatch /tmp/sample"]
+        );
+
+        registry.remove(&mut watcher, child, &1).unwrap();
+        assert_eq!(watcher.take(), ["unwatch /tmp/sample/subdir"]);
+    }
+
+    #[test]
+    fn test_non_recursive() {
+        let mut watcher = Watcher::default();
+        let mut registry = WatchRegistry::new();
+        let parent = Path::new("/tmp/sample");
+        let child = Path::new("/tmp/sample/subdir");
+
+        registry
+            .add(&mut watcher, parent, RecursiveMode::NonRecursive, 1)
+            .unwrap();
+        registry
+            .add(&mut watcher, child, RecursiveMode::Recursive, 1)
+            .unwrap();
+        assert_eq!(
+            watcher.take(),
+            [
+                "watch /tmp/sample NonRecursive",
+                "watch /tmp/sample/subdir Recursive"
+            ]
+        );
+        assert!(!registry.is_watching(Path::new("/tmp/sample/other/filename")));
+        assert!(registry.is_watching(Path::new("/tmp/sample/subdir/filename")));
+
+        // Upgraded to recursive.
+        registry
+            .add(&mut watcher, parent, RecursiveMode::Recursive, 2)
+            .unwrap();
+        let mut calls = watcher.take();
+        calls.sort();
+        assert_eq!(
+            calls,
+            [
+                "unwatch /tmp/sample",
+                "unwatch /tmp/sample/subdir",
+                "watch /tmp/sample Recursive"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_watch() {
+        let mut watcher = Watcher::default();
+        let mut registry = WatchRegistry::new();
+
+        assert!(
+            registry
+                .add(
+                    &mut watcher,
+                    Path::new("/missing"),
+                    RecursiveMode::Recursive,
+                    1
+                )
+                .is_err()
+        );
+        assert!(watcher.take().is_empty());
+        assert_eq!(registry.paths_of(&1).count(), 0);
+    }
+}
@@ -0,0 +1,157 @@
+//! Standalone watching, driving a [`Monitor`] the way unison does.
+
+use crate::event::FsEvent;
+use crate::monitor::{Event, Monitor};
+use crate::protocol::{Request, Response};
+use crate::watch::Watch;
+use failure::Fallible;
+use std::path::{Path, PathBuf};
+
+/// Replica id used by [`WatchSession`].
+pub const WATCH_REPLICA: &str = "watch";
+
+/// Drives a monitor the same way unison does, i.e., START, WAIT and CHANGES.
+pub struct WatchSession<WATCH: Watch> {
+    /// Monitor being driven, replies are collected in its writer.
+    pub monitor: Monitor<WATCH, Vec<u8>>,
+}
+
+impl<WATCH: Watch> WatchSession<WATCH> {
+    /// Starts watching root, or only subdirs of root if any, following links. Both subdirs and
+    /// links are relative to root.
+    pub fn start(
+        watcher: WATCH,
+        root: &Path,
+        subdirs: &[String],
+        links: &[String],
+    ) -> Fallible<Self> {
+        Self::start_with(Monitor::new(watcher, vec![]), root, subdirs, links)
+    }
+
+    /// Same as [`WatchSession::start`], driving a monitor set up by the caller, e.g., with
+    /// [`Monitor::normalization`] set.
+    pub fn start_with(
+        monitor: Monitor<WATCH, Vec<u8>>,
+        root: &Path,
+        subdirs: &[String],
+        links: &[String],
+    ) -> Fallible<Self> {
+        let mut session = Self { monitor };
+
+        if subdirs.is_empty() {
+            session.request(Request::Start {
+                replica: WATCH_REPLICA.into(),
+                root: root.into(),
+                subdir: None,
+            })?;
+        }
+        for subdir in subdirs {
+            session.request(Request::Start {
+                replica: WATCH_REPLICA.into(),
+                root: root.into(),
+                subdir: Some(subdir.into()),
+            })?;
+        }
+        for link in links {
+            session.request(Request::Link(Some(root.join(link))))?;
+        }
+        session.request(Request::Wait {
+            replica: WATCH_REPLICA.into(),
+        })?;
+
+        Ok(session)
+    }
+
+    /// Feeds a filesystem event to the monitor. Returns the changed paths, relative to root, if
+    /// the monitor reported any.
+    pub fn handle_fsevent(&mut self, event: FsEvent) -> Fallible<Vec<PathBuf>> {
+        self.handle_event(Event::FSEvent(event))
+    }
+
+    /// Feeds an event to the monitor, e.g., [`Event::Tick`] for checking mounts. Returns the
+    /// changed paths, relative to root, if the monitor reported any.
+    pub fn handle_event(&mut self, event: Event) -> Fallible<Vec<PathBuf>> {
+        self.monitor.handle_event(event)?;
+        if !self
+            .replies()?
+            .iter()
+            .any(|response| matches!(response, Response::Changes { .. }))
+        {
+            return Ok(vec![]);
+        }
+
+        let mut changes: Vec<PathBuf> = self
+            .request(Request::Changes {
+                replica: WATCH_REPLICA.into(),
+            })?
+            .into_iter()
+            .filter_map(|response| match response {
+                Response::Recursive(path) => Some(path),
+                _ => None,
+            })
+            .collect();
+        changes.sort();
+        self.request(Request::Wait {
+            replica: WATCH_REPLICA.into(),
+        })?;
+
+        Ok(changes)
+    }
+
+    /// Sends a request to the monitor as unison would and returns the replies.
+    fn request(&mut self, request: Request) -> Fallible<Vec<Response>> {
+        self.monitor
+            .handle_event(Event::Input(request.to_string()))?;
+        self.replies()
+    }
+
+    fn replies(&mut self) -> Fallible<Vec<Response>> {
+        let output = String::from_utf8(std::mem::take(&mut self.monitor.writer))?;
+        output.lines().map(Response::parse).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::EventKind;
+
+    struct Watcher {}
+
+    impl Watch for Watcher {}
+
+    #[test]
+    fn test_watch_session() {
+        let root = "/tmp/sample";
+        let mut session =
+            WatchSession::start(Watcher {}, Path::new(root), &["sub dir".into()], &[]).unwrap();
+
+        assert!(
+            session
+                .monitor
+                .replicas
+                .get(WATCH_REPLICA)
+                .unwrap()
+                .paths
+                .contains(&PathBuf::from(root).join("sub dir"))
+        );
+        assert_eq!(
+            session
+                .handle_fsevent(FsEvent::new(
+                    EventKind::Create,
+                    &PathBuf::from(root).join("sub dir").join("filename")
+                ))
+                .unwrap(),
+            vec![PathBuf::from("sub dir/filename")]
+        );
+        assert!(
+            session
+                .handle_fsevent(FsEvent::new(
+                    EventKind::Create,
+                    &PathBuf::from("/elsewhere")
+                ))
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Trie of paths by components, for looking up all prefixes of a path in O(depth).
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Values keyed by paths, where several values may share a path.
+pub struct PathTrie<T> {
+    root: Node<T>,
+}
+
+struct Node<T> {
+    values: Vec<T>,
+    children: HashMap<OsString, Node<T>>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Self {
+            values: vec![],
+            children: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Default for PathTrie<T> {
+    fn default() -> Self {
+        Self {
+            root: Node::default(),
+        }
+    }
+}
+
+impl<T: PartialEq> PathTrie<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds value at path, unless already there.
+    pub fn insert(&mut self, path: &Path, value: T) {
+        let node = path.components().fold(&mut self.root, |node, component| {
+            node.children
+                .entry(component.as_os_str().to_owned())
+                .or_default()
+        });
+        if !node.values.contains(&value) {
+            node.values.push(value);
+        }
+    }
+
+    /// Removes value at path. Returns whether it was there.
+    pub fn remove(&mut self, path: &Path, value: &T) -> bool {
+        fn remove<T: PartialEq>(
+            node: &mut Node<T>,
+            mut components: std::path::Components,
+            value: &T,
+        ) -> bool {
+            match components.next() {
+                None => {
+                    let len = node.values.len();
+                    node.values.retain(|v| v != value);
+                    node.values.len() != len
+                }
+                Some(component) => {
+                    let key = component.as_os_str();
+                    let Some(child) = node.children.get_mut(key) else {
+                        return false;
+                    };
+                    let removed = remove(child, components, value);
+                    if child.values.is_empty() && child.children.is_empty() {
+                        node.children.remove(key);
+                    }
+                    removed
+                }
+            }
+        }
+
+        remove(&mut self.root, path.components(), value)
+    }
+
+    /// Values at path and at all its ancestors, shortest first, along with the number of
+    /// components of the path they are at.
+    pub fn prefixes<'a>(&'a self, path: &Path) -> Vec<(usize, &'a T)> {
+        let mut node = &self.root;
+        let mut prefixes: Vec<(usize, &T)> = node.values.iter().map(|v| (0, v)).collect();
+        for (idx, component) in path.components().enumerate() {
+            match node.children.get(component.as_os_str()) {
+                Some(child) => node = child,
+                None => break,
+            }
+            prefixes.extend(node.values.iter().map(|v| (idx + 1, v)));
+        }
+        prefixes
+    }
+
+    /// Check if there is any value at path or one of its ancestors.
+    pub fn contains_prefix_of(&self, path: &Path) -> bool {
+        !self.prefixes(path).is_empty()
+    }
+
+    /// All paths and values.
+    pub fn iter(&self) -> Vec<(PathBuf, &T)> {
+        fn collect<'a, T>(node: &'a Node<T>, path: &mut PathBuf, out: &mut Vec<(PathBuf, &'a T)>) {
+            out.extend(node.values.iter().map(|v| (path.clone(), v)));
+            for (component, child) in &node.children {
+                path.push(component);
+                collect(child, path, out);
+                path.pop();
+            }
+        }
+
+        let mut out = vec![];
+        collect(&self.root, &mut PathBuf::new(), &mut out);
+        out
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.values.is_empty() && self.root.children.is_empty()
+    }
+}
+
+impl<T: PartialEq + fmt::Debug> fmt::Debug for PathTrie<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// Path without its first n components.
+pub fn skip_components(path: &Path, n: usize) -> PathBuf {
+    path.components().skip(n).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prefixes() {
+        let mut trie = PathTrie::new();
+        trie.insert(Path::new("/tmp"), 1);
+        trie.insert(Path::new("/tmp/sample"), 2);
+        trie.insert(Path::new("/tmp/sample"), 3);
+        trie.insert(Path::new("/tmp/sample"), 3);
+        trie.insert(Path::new("/tmp/other"), 4);
+
+        assert_eq!(
+            trie.prefixes(Path::new("/tmp/sample/subdir/filename")),
+            [(2, &1), (3, &2), (3, &3)]
+        );
+        assert_eq!(
+            trie.prefixes(Path::new("/tmp/sample")),
+            [(2, &1), (3, &2), (3, &3)]
+        );
+        assert!(trie.prefixes(Path::new("/usr")).is_empty());
+        assert!(!trie.contains_prefix_of(Path::new("/tm")));
+        assert_eq!(
+            skip_components(Path::new("/tmp/sample/subdir/filename"), 3),
+            Path::new("subdir/filename")
+        );
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut trie = PathTrie::new();
+        trie.insert(Path::new("/tmp/sample"), 1);
+        trie.insert(Path::new("/tmp/sample/subdir"), 2);
+
+        assert!(!trie.remove(Path::new("/tmp/sample"), &2));
+        assert!(trie.remove(Path::new("/tmp/sample/subdir"), &2));
+        assert_eq!(trie.iter(), [(PathBuf::from("/tmp/sample"), &1)]);
+        assert!(trie.remove(Path::new("/tmp/sample"), &1));
+        assert!(trie.is_empty());
+    }
+}
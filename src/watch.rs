@@ -0,0 +1,365 @@
+//! Filesystem watching backends.
+
+#[cfg(target_os = "linux")]
+use crate::event::EventKind;
+use crate::event::{FsEvent, adapt_raw};
+#[cfg(target_os = "linux")]
+use crate::fanotify::FanotifyWatcher;
+use crate::limits::is_watch_limit;
+use crate::mounts::{mount_of, read_mounts};
+use crate::poll::PollWatcher;
+use failure::{Fallible, bail};
+use log::info;
+#[cfg(target_os = "linux")]
+use log::{debug, warn};
+use notify::{RecommendedWatcher, RecursiveMode};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+/// Backend used by [`Monitor`](crate::Monitor) to add and remove filesystem watches.
+///
+/// Events of watched paths are expected to be fed back into the monitor by the caller, see
+/// [`Monitor::run`](crate::Monitor::run). Watching and unwatching default to doing nothing, which
+/// is convenient for tests.
+pub trait Watch {
+    /// Starts watching path.
+    fn watch(&mut self, _path: &Path, _recursive_mode: RecursiveMode) -> Fallible<()> {
+        Ok(())
+    }
+
+    /// Stops watching path.
+    fn unwatch(&mut self, _path: &Path) -> Fallible<()> {
+        Ok(())
+    }
+
+    /// Makes sure that path, a directory just created under a recursively watched path, is
+    /// watched by the time this returns. The directory is then reported RECURSIVE, and unison
+    /// scans it without missing anything. Backends watching new directories synchronously
+    /// don't need to do anything.
+    fn watch_created(&mut self, _path: &Path) -> Fallible<()> {
+        Ok(())
+    }
+
+    /// Starts watching path by polling, used when [`Watch::watch`] runs out of watches. Once
+    /// polled, a path is stopped with [`Watch::unwatch`] as usual.
+    fn poll(&mut self, path: &Path, _recursive_mode: RecursiveMode) -> Fallible<()> {
+        bail!("Polling {:?} not supported", path)
+    }
+}
+
+impl Watch for RecommendedWatcher {
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Fallible<()> {
+        Ok(notify::Watcher::watch(self, path, recursive_mode)?)
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Fallible<()> {
+        Ok(notify::Watcher::unwatch(self, path)?)
+    }
+
+    /// inotify watches new directories once it gets to their events, watching them right away
+    /// doesn't hurt as they are dropped along with the watch of their parent.
+    #[cfg(target_os = "linux")]
+    fn watch_created(&mut self, path: &Path) -> Fallible<()> {
+        Ok(notify::Watcher::watch(
+            self,
+            path,
+            RecursiveMode::Recursive,
+        )?)
+    }
+}
+
+/// Kind of watching backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Events of the platform, e.g., inotify on Linux.
+    Native,
+    /// Polling, see [`PollWatcher`].
+    Poll,
+    /// fanotify marks on whole filesystems on Linux, falling back to native events when the
+    /// kernel or the capabilities of the process don't allow them.
+    Fanotify,
+    /// Polling for network and FUSE filesystems, fanotify where allowed, native otherwise.
+    Auto,
+}
+
+impl FromStr for Backend {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Fallible<Backend> {
+        Ok(match s {
+            "native" => Backend::Native,
+            "poll" => Backend::Poll,
+            "fanotify" => Backend::Fanotify,
+            "auto" => Backend::Auto,
+            _ => bail!(
+                "Unknown backend: {} (expected native, poll, fanotify or auto)",
+                s
+            ),
+        })
+    }
+}
+
+/// Settings of [`BackendWatcher`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackendOptions {
+    pub backend: Backend,
+    /// Interval between polls.
+    pub poll_interval: Duration,
+    /// Number of files checked for modifications per poll.
+    pub poll_batch: usize,
+}
+
+impl Default for BackendOptions {
+    fn default() -> Self {
+        Self {
+            backend: Backend::Auto,
+            poll_interval: crate::poll::DEFAULT_INTERVAL,
+            poll_batch: crate::poll::DEFAULT_BATCH,
+        }
+    }
+}
+
+/// Backend a path is watched with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Via {
+    Native,
+    #[cfg(target_os = "linux")]
+    Fanotify,
+    Poll,
+}
+
+/// Watches each path with the backend chosen by [`BackendOptions`].
+pub struct BackendWatcher {
+    backend: Backend,
+    native: Option<RecommendedWatcher>,
+    #[cfg(target_os = "linux")]
+    fanotify: Option<FanotifyWatcher>,
+    poll: PollWatcher,
+    watched: HashMap<PathBuf, (Via, RecursiveMode)>,
+    /// For rescans of paths whose inotify watches had to be added again.
+    #[cfg(target_os = "linux")]
+    tx: Sender<FsEvent>,
+}
+
+impl BackendWatcher {
+    /// Creates backends sending events to tx.
+    pub fn new(options: &BackendOptions, tx: Sender<FsEvent>) -> Fallible<Self> {
+        let native = match options.backend {
+            Backend::Poll => None,
+            _ => Some(notify::Watcher::new_raw(adapt_raw(tx.clone()))?),
+        };
+        #[cfg(target_os = "linux")]
+        let fanotify = match options.backend {
+            Backend::Fanotify | Backend::Auto => match FanotifyWatcher::new(tx.clone()) {
+                Ok(fanotify) => Some(fanotify),
+                Err(e) if options.backend == Backend::Fanotify => {
+                    warn!("{}, falling back to native events", e);
+                    None
+                }
+                Err(e) => {
+                    debug!("{}", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        #[cfg(not(target_os = "linux"))]
+        if options.backend == Backend::Fanotify {
+            bail!("fanotify is only available on Linux");
+        }
+        Ok(Self {
+            backend: options.backend,
+            native,
+            #[cfg(target_os = "linux")]
+            fanotify,
+            poll: PollWatcher::new(tx.clone(), options.poll_interval, options.poll_batch),
+            watched: HashMap::new(),
+            #[cfg(target_os = "linux")]
+            tx,
+        })
+    }
+
+    /// Whether path gets watched with fanotify, which needs no inotify watches.
+    pub fn uses_fanotify(&mut self, path: &Path) -> bool {
+        #[cfg(target_os = "linux")]
+        if !self.should_poll(path)
+            && let Some(fanotify) = &mut self.fanotify
+            && fanotify.watch(path, RecursiveMode::Recursive).is_ok()
+        {
+            let _ = fanotify.unwatch(path);
+            return true;
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = path;
+        false
+    }
+
+    fn should_poll(&self, path: &Path) -> bool {
+        match self.backend {
+            Backend::Native | Backend::Fanotify => false,
+            Backend::Poll => true,
+            Backend::Auto => {
+                let mounts = read_mounts();
+                match mount_of(&mounts, path) {
+                    Some(mount) if mount.is_remote() => {
+                        info!(
+                            "Polling {:?} on {} filesystem {}",
+                            path, mount.fs_type, mount.source
+                        );
+                        true
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+impl Watch for BackendWatcher {
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Fallible<()> {
+        let poll = self.should_poll(path);
+        #[cfg(target_os = "linux")]
+        if !poll && let Some(fanotify) = &mut self.fanotify {
+            match fanotify.watch(path, recursive_mode) {
+                Ok(()) => {
+                    self.watched
+                        .insert(path.to_owned(), (Via::Fanotify, recursive_mode));
+                    return Ok(());
+                }
+                Err(e) => warn!("{}, falling back to native events", e),
+            }
+        }
+        match &mut self.native {
+            Some(native) if !poll => {
+                if let Err(e) = native.watch(path, recursive_mode) {
+                    if is_watch_limit(&e) {
+                        // Drop watches added before running out.
+                        let _ = native.unwatch(path);
+                    }
+                    return Err(e);
+                }
+            }
+            _ => self.poll.watch(path, recursive_mode)?,
+        }
+        let via = if poll { Via::Poll } else { Via::Native };
+        self.watched.insert(path.to_owned(), (via, recursive_mode));
+        Ok(())
+    }
+
+    fn poll(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Fallible<()> {
+        if self.backend == Backend::Native {
+            bail!("Polling {:?} disabled by the native backend", path);
+        }
+        self.poll.watch(path, recursive_mode)?;
+        self.watched
+            .insert(path.to_owned(), (Via::Poll, recursive_mode));
+        Ok(())
+    }
+
+    fn watch_created(&mut self, path: &Path) -> Fallible<()> {
+        // fanotify marks whole filesystems and polling lists new directories by itself.
+        let via = path.ancestors().find_map(|base| self.watched.get(base));
+        match (via, &mut self.native) {
+            (Some((Via::Native, _)), Some(native)) => native.watch_created(path),
+            _ => Ok(()),
+        }
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Fallible<()> {
+        match self.watched.remove(path) {
+            Some((Via::Native, _recursive_mode)) => {
+                let Some(native) = &mut self.native else {
+                    return Ok(());
+                };
+                // inotify watches directories rather than paths, shared by overlapping watches.
+                // Those of path belong to a recursive watch of an ancestor as well, and removing
+                // a recursive watch removes those of the watches under it.
+                #[cfg(target_os = "linux")]
+                if path.ancestors().skip(1).any(|base| {
+                    self.watched.get(base) == Some(&(Via::Native, RecursiveMode::Recursive))
+                }) {
+                    debug!("Leaving watches of {:?} to a watched ancestor", path);
+                    return Ok(());
+                }
+                native.unwatch(path)?;
+                #[cfg(target_os = "linux")]
+                if _recursive_mode == RecursiveMode::Recursive {
+                    let under: Vec<(PathBuf, RecursiveMode)> = self
+                        .watched
+                        .iter()
+                        .filter(|(base, (via, _))| *via == Via::Native && base.starts_with(path))
+                        .map(|(base, (_, mode))| (base.clone(), *mode))
+                        .collect();
+                    for (base, mode) in under {
+                        debug!("Watching {:?} again after unwatching {:?}", base, path);
+                        if let Err(e) = native.watch(&base, mode) {
+                            warn!("Unable to watch {:?} again: {}", base, e);
+                        }
+                        // Events in between were missed.
+                        let _ = self.tx.send(FsEvent::new(EventKind::Rescan, &base));
+                    }
+                }
+                Ok(())
+            }
+            #[cfg(target_os = "linux")]
+            Some((Via::Fanotify, _)) => match &mut self.fanotify {
+                Some(fanotify) => fanotify.unwatch(path),
+                None => Ok(()),
+            },
+            _ => self.poll.unwatch(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::sync::mpsc::{Receiver, channel};
+
+    /// Whether an event for path arrives.
+    fn receives(rx: &Receiver<FsEvent>, path: &Path) -> bool {
+        while let Ok(event) = rx.recv_timeout(Duration::from_secs(2)) {
+            if event.paths.iter().any(|p| p == path) {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn test_overlapping_native() {
+        let parent =
+            std::env::temp_dir().join(format!("unison-fsmonitor-overlap-{}", std::process::id()));
+        let child = parent.join("subdir");
+        let _ = fs::remove_dir_all(&parent);
+        fs::create_dir_all(child.join("nested")).unwrap();
+        let parent = parent.canonicalize().unwrap();
+        let child = child.canonicalize().unwrap();
+        let options = BackendOptions {
+            backend: Backend::Native,
+            ..BackendOptions::default()
+        };
+        let (tx, rx) = channel();
+        let mut watcher = BackendWatcher::new(&options, tx).unwrap();
+
+        // Moving a watch up to the parent, as the registry does, keeps the child watched.
+        watcher.watch(&child, RecursiveMode::Recursive).unwrap();
+        watcher.watch(&parent, RecursiveMode::Recursive).unwrap();
+        watcher.unwatch(&child).unwrap();
+        fs::write(child.join("nested/up"), "").unwrap();
+        assert!(receives(&rx, &child.join("nested/up")));
+
+        // And moving it back down.
+        watcher.watch(&child, RecursiveMode::Recursive).unwrap();
+        watcher.unwatch(&parent).unwrap();
+        fs::write(child.join("nested/down"), "").unwrap();
+        assert!(receives(&rx, &child.join("nested/down")));
+
+        watcher.unwatch(&child).unwrap();
+        fs::remove_dir_all(&parent).unwrap();
+    }
+}